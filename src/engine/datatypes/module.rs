@@ -16,7 +16,15 @@ use std::collections::HashMap;
 #[derive(Clone, Debug, Default)]
 pub struct ServModule {
     pub values: HashMap<Label, ServValue>,
-    pub statements: Vec<ServList>
+    pub statements: Vec<ServList>,
+
+    /// `@shutdown` statements, which the webserver runs before exiting
+    pub shutdown: Vec<ServList>,
+
+    /// `@before` and `@after` middleware, which the webserver runs around every route
+    /// that matches their pattern, or every route if they don't have one
+    pub before: Vec<(Option<String>, ServList)>,
+    pub after: Vec<(Option<String>, ServList)>,
 }
 
 impl ServModule {
@@ -24,6 +32,9 @@ impl ServModule {
         Self {
             values: HashMap::new(),
             statements: Vec::new(),
+            shutdown: Vec::new(),
+            before: Vec::new(),
+            after: Vec::new(),
         }
     }
 
@@ -65,14 +76,20 @@ impl ServModule {
         })
     }
 
+    pub fn sockets(&self) -> impl Iterator<Item=(&str, &ServValue)> {
+        self.values.iter().filter_map(|(l, v)| match l {
+            Label::Socket(name) => Some((name.as_str(), v)),
+            _ => None,
+        })
+    }
 
     pub fn call(self, input: Option<ServValue>, scope: &mut Stack) -> ServResult {
-		if self.statements.len() == 0 && self.values.len() == 0 {
+		if self.statements.is_empty() && self.values.is_empty() {
     		let Some(output) = input else {return Ok(ServValue::None)};
     		return Ok(output);
 		}
 
-		if self.statements.len() == 0 {
+		if self.statements.is_empty() {
     		let mut table = HashMap::new();
     		for (key, value) in self.values.into_iter() {
         		table.insert(key.to_string(), value.call(input.clone(), scope)?);
@@ -5,6 +5,9 @@ use std::fmt::Display;
 pub enum Label {
     Name(String),
     Route(String),
+
+    /// A route declared with `ws`, which only matches websocket connections
+    Socket(String),
 }
 
 impl Label {
@@ -12,6 +15,7 @@ impl Label {
         match self {
             Self::Name(v) => v.as_str(),
             Self::Route(v) => v.as_str(),
+            Self::Socket(v) => v.as_str(),
         }
     }
  }
@@ -76,6 +80,7 @@ impl Display for Label {
         match (self) {
             Self::Name(s) => f.write_str(s)?,
             Self::Route(s) => f.write_str(s)?,
+            Self::Socket(s) => write!(f, "ws {}", s)?,
             // Self::Anonymous(id) => write!(f, "anonymous function {}", id)?,
         };
 
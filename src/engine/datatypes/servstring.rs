@@ -36,6 +36,9 @@ impl From<&[u8]> for Data {
 pub struct ServString {
     pub mime: Option<&'static str>,
     data: Data,
+
+    /// Whether the text is trusted html, which html templates insert without escaping
+    safe: bool,
 }
 
 impl ServString {
@@ -62,17 +65,32 @@ impl ServString {
    pub fn from_bytes<T: Into<Bytes>>(input: T) -> Self {
         let data: Bytes = input.into();
         if let Ok(text) = std::str::from_utf8(&data) {
-            Self { mime: None, data: text.into() }
+            Self { mime: None, data: text.into(), safe: false }
         }
         else {
-            Self { mime: None, data: data.into() }
+            Self { mime: None, data: data.into(), safe: false }
         }
 
     }
 
+    /// Keep data as bytes even if it happens to be valid utf-8, like a blob read from a database
+    pub fn from_binary<T: Into<Bytes>>(input: T) -> Self {
+        Self { mime: None, data: Data::Bytes(input.into()), safe: false }
+    }
+
     pub fn from_text<T: Into<String>>(input: T) -> Self {
         let data: String = input.into();
-        Self { mime: None, data: data.into() }
+        Self { mime: None, data: data.into(), safe: false }
+    }
+
+    pub fn is_safe(&self) -> bool {
+        self.safe
+    }
+
+    /// Mark text as html that doesn't need escaping, like the output of `markdown`
+    pub fn mark_safe(mut self) -> Self {
+        self.safe = true;
+        self
     }
 
     pub fn as_value(self) -> ServValue {
@@ -82,6 +100,6 @@ impl ServString {
 
 impl<I> From<I> for ServString where I: Into<Data> {
     fn from(input: I) -> Self {
-        Self { mime: None, data: input.into() }
+        Self { mime: None, data: input.into(), safe: false }
     }
 }
@@ -1,8 +1,10 @@
 use crate::value::ServValue;
 use crate::{ Stack, ServResult, ServFn };
 use std::fmt::Display;
+use std::cell::Cell;
 use crate::value::Serializer;
 use crate::ServError;
+use crate::ServString;
 
 type Buffer<'a> = dyn std::fmt::Write + 'a;
 
@@ -15,6 +17,7 @@ pub trait Renderer {
 pub struct DefaultRenderer<'scope, S: Serializer + Clone> {
     include_brackets: bool,
     resolve_expressions: bool,
+    escape_html: bool,
 	serializer: S,
 	scope: &'scope Stack<'scope>
 }
@@ -26,6 +29,7 @@ impl<'a> DefaultRenderer<'a, json::JsonSerializer<'a>> {
         Self {
             include_brackets: false,
             resolve_expressions: true,
+            escape_html: false,
             serializer: json::serializer(scope),
             scope: scope,
         }
@@ -55,6 +59,8 @@ impl<'scope, S: Serializer + Clone> Renderer for DefaultRenderer<'scope, S> {
                     let value = t.call(input, &ctx)?;
                     match value {
                         ServValue::Module(m) => {ctx.insert_module(m.values)},
+                        ServValue::Text(ref t) if t.is_safe() => { dest.write_str(&value.to_string()); },
+                        value if self.escape_html => { dest.write_str(&escape_html(&value.to_string())); },
                         value => { dest.write_str(&value.to_string()); },
                     };
                 },
@@ -75,6 +81,23 @@ impl<'scope, S: Serializer + Clone> Renderer for DefaultRenderer<'scope, S> {
     }
 }
 
+/// Escape text so that it can be put anywhere in an html document, including inside of a quoted attribute
+pub fn escape_html(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&'  => output.push_str("&amp;"),
+            '<'  => output.push_str("&lt;"),
+            '>'  => output.push_str("&gt;"),
+            '"'  => output.push_str("&quot;"),
+            '\'' => output.push_str("&#39;"),
+            c    => output.push(c),
+        }
+    }
+
+    output
+}
+
 #[derive(Clone)]
 pub struct LiteralRenderer {
     include_brackets: bool,
@@ -127,15 +150,54 @@ pub struct Template {
 }
 
 impl Template {
+    /// Templates with markup anywhere in them, like `{hello <b>$name</b>}`, produce html
+    pub fn is_html(&self) -> bool {
+        self.elements.iter().any(|e| match e {
+            TemplateElement::Text(t) => has_tag(t),
+            TemplateElement::Template(t) => t.is_html(),
+            TemplateElement::Expression(_) => false,
+        })
+    }
+
+    /// Render the template, escaping interpolated values unless it is known not to produce html,
+    /// because it has no markup and the response has been given another type with `res.mime`.
+    /// Without a type, browsers will guess that a response is html. The output of an html
+    /// template is marked safe, so that it isn't escaped again when put into another one.
     pub fn render(&self, ctx: &Stack) -> ServResult {
         let mut output = String::new();
         let mut renderer = DefaultRenderer::new(ctx);
+        renderer.escape_html = self.is_html() || !is_other_response(ctx);
 
         renderer.render(self, &mut output)?;
-		Ok(ServValue::Text(output.into()))
+
+        let output = ServString::from(output);
+		Ok(ServValue::Text(if renderer.escape_html { output.mark_safe() } else { output }))
     }
 }
 
+/// Whether text contains the start of a tag, like `<p`, `</p` or `<!--`
+fn has_tag(text: &str) -> bool {
+    text.match_indices('<').any(|(i, _)| {
+        text[i + 1..].chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '!' || c == '/')
+    })
+}
+
+thread_local! {
+    /// Set while reading `res.mime`, which is usually a template itself
+    static READING_MIME: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the response has been given a content type other than html with `res.mime`
+fn is_other_response(ctx: &Stack) -> bool {
+    if READING_MIME.get() { return true };
+
+    READING_MIME.set(true);
+    let mime = crate::engine::deref(&"res.mime".into(), ctx).and_then(|mime| mime.call(None, ctx));
+    READING_MIME.set(false);
+
+    mime.is_ok_and(|mime| !mime.to_string().trim().starts_with("text/html"))
+}
+
 impl Display for Template {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         let mut r = LiteralRenderer { include_brackets: true };
@@ -14,11 +14,14 @@ use std::collections::hash_map::Entry;
 use hyper::body::{Body, Frame, Incoming as IncomingBody};
 use hyper::{ Request, Response };
 use hyper::http::request::Parts;
+use std::sync::Arc;
+use super::pool::{SqlitePool, Transaction};
 
 pub use crate::datatypes::reference::{Label, Address};
 
+#[derive(Clone, Debug)]
 pub enum DatabaseConnection {
-    Sqlite(sqlite::ConnectionThreadSafe),
+    Sqlite(Arc<SqlitePool>),
 }
 
 pub struct StackDictionary<'parent, V> {
@@ -26,6 +29,12 @@ pub struct StackDictionary<'parent, V> {
 	parent: Option<&'parent Self>,
 
 	pub connection: Option<DatabaseConnection>,
+
+	/// databases opened with `sqlite.connect (name = {name}) {file}`
+	pub connections: HashMap<String, DatabaseConnection>,
+
+	/// the transaction that queries in this scope should run in, if any
+	pub transaction: Option<Arc<Transaction>>,
 	pub words: HashMap<Label, V>,
 	pub request: Option<Parts>,
 }
@@ -39,6 +48,8 @@ impl<'parent, V: Clone> StackDictionary<'parent, V> {
             request: None,
 
             connection: None,
+            connections: HashMap::new(),
+            transaction: None,
         }
     }
 
@@ -49,6 +60,8 @@ impl<'parent, V: Clone> StackDictionary<'parent, V> {
             parent: Some(self),
             request: None,
             connection: None,
+            connections: HashMap::new(),
+            transaction: None,
         }
     }
 
@@ -85,6 +98,14 @@ impl<'parent, V: Clone> StackDictionary<'parent, V> {
     pub fn get_database_connection(&self) -> Option<&DatabaseConnection> {
         self.connection.as_ref().or_else(|| self.parent.and_then(|p| p.get_database_connection()))
     }
+
+    pub fn get_transaction(&self) -> Option<&Arc<Transaction>> {
+        self.transaction.as_ref().or_else(|| self.parent.and_then(|p| p.get_transaction()))
+    }
+
+    pub fn get_named_connection(&self, name: &str) -> Option<&DatabaseConnection> {
+        self.connections.get(name).or_else(|| self.parent.and_then(|p| p.get_named_connection(name)))
+    }
 }
 
 pub type Stack<'a> = StackDictionary<'a, ServValue>;
@@ -126,7 +147,8 @@ mod tests {
 
 	#[test]
 	fn test() {
-    	let mut one: StackDictionary<String, Vec<String>> = StackDictionary::empty();
-    	one.insert("hello".to_owned(), vec![])
+    	let mut one: StackDictionary<ServValue> = StackDictionary::empty();
+    	one.insert("hello", ServValue::List(crate::ServList::new())).unwrap();
+    	assert!(matches!(one.get("hello"), Ok(ServValue::List(_))));
 	}
 }
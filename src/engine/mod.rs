@@ -1,5 +1,6 @@
 pub mod datatypes;
 pub mod dictionary;
+pub mod pool;
 
 pub use datatypes::value;
 
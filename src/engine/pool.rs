@@ -0,0 +1,328 @@
+use crate::ServError;
+
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How connections to a database are opened, set by the options given to `sqlite.connect`
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    pub size: usize,
+    pub readonly: bool,
+    pub busy_timeout: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self { size: 4, readonly: false, busy_timeout: Duration::from_secs(5) }
+    }
+}
+
+struct PoolState {
+    idle: Vec<sqlite::ConnectionThreadSafe>,
+    open: usize,
+}
+
+/// A set of connections to one sqlite database, opened as they are needed up to a limit.
+/// Requests that find every connection busy wait for one to be returned.
+pub struct SqlitePool {
+    location: String,
+    options: PoolOptions,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+impl std::fmt::Debug for SqlitePool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "SqlitePool({})", self.location)
+    }
+}
+
+impl SqlitePool {
+    /// Open a pool, connecting once straight away so that a bad path is reported on startup
+    pub fn open(location: &str, mut options: PoolOptions) -> Result<Self, ServError> {
+        // every connection to an in memory database gets a separate, empty database
+        if location == ":memory:" { options.size = 1 };
+        options.size = options.size.max(1);
+
+        let pool = Self {
+            location: location.to_owned(),
+            options,
+            state: Mutex::new(PoolState { idle: Vec::new(), open: 0 }),
+            returned: Condvar::new(),
+        };
+
+        let first = pool.connect()?;
+        if !pool.options.readonly {
+            first.execute("PRAGMA journal_mode=WAL;")?;
+        }
+
+        let mut state = pool.lock();
+        state.idle.push(first);
+        state.open = 1;
+        drop(state);
+
+        Ok(pool)
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.options.readonly
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn connect(&self) -> Result<sqlite::ConnectionThreadSafe, ServError> {
+        let flags = match self.options.readonly {
+            true  => sqlite::OpenFlags::new().with_read_only().with_full_mutex(),
+            false => sqlite::OpenFlags::new().with_create().with_read_write().with_full_mutex(),
+        };
+
+        let mut connection = sqlite::Connection::open_thread_safe_with_flags(&self.location, flags)
+            .map_err(|e| ServError::new(500, &format!("could not open {}: {}", self.location, e)))?;
+
+        let millis = self.options.busy_timeout.as_millis().try_into().unwrap_or(usize::MAX);
+        connection.set_busy_timeout(millis)?;
+        Ok(connection)
+    }
+
+    /// Open a connection outside of the pool, for work like streaming a response to a slow
+    /// client that could hold on to it for a long time. An in memory database can't be opened
+    /// twice, so there is none for those.
+    pub fn connect_dedicated(&self) -> Result<Option<sqlite::ConnectionThreadSafe>, ServError> {
+        if self.location == ":memory:" { return Ok(None) };
+        self.connect().map(Some)
+    }
+
+    /// Take a connection from the pool, blocking until one is free. This should only be called
+    /// from a blocking thread, never directly on the async runtime.
+    pub fn get(self: &Arc<Self>) -> Result<PooledConnection, ServError> {
+        let mut state = self.lock();
+        loop {
+            if let Some(connection) = state.idle.pop() {
+                return Ok(PooledConnection::new(self.clone(), connection))
+            }
+
+            if state.open < self.options.size {
+                state.open += 1;
+                drop(state);
+
+                return match self.connect() {
+                    Ok(connection) => Ok(PooledConnection::new(self.clone(), connection)),
+                    Err(e) => {
+                        self.lock().open -= 1;
+                        Err(e)
+                    },
+                }
+            }
+
+            state = self.returned.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// A connection borrowed from a pool, which goes back to the pool when dropped
+pub struct PooledConnection {
+    pool: Arc<SqlitePool>,
+    connection: Option<sqlite::ConnectionThreadSafe>,
+    discarded: AtomicBool,
+}
+
+impl PooledConnection {
+    fn new(pool: Arc<SqlitePool>, connection: sqlite::ConnectionThreadSafe) -> Self {
+        Self { pool, connection: Some(connection), discarded: AtomicBool::new(false) }
+    }
+
+    /// Close the connection when it is dropped instead of returning it to the pool, for when
+    /// it is left in a state that the next request to use it shouldn't see
+    pub fn discard(&self) {
+        self.discarded.store(true, Ordering::Relaxed);
+    }
+}
+
+impl std::ops::Deref for PooledConnection {
+    type Target = sqlite::ConnectionThreadSafe;
+
+    fn deref(&self) -> &Self::Target {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            match self.discarded.load(Ordering::Relaxed) {
+                true  => self.pool.lock().open -= 1,
+                false => self.pool.lock().idle.push(connection),
+            }
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+/// A connection pinned for the length of a `sqlite.transaction`, so that every query inside
+/// of it runs on the same connection. Nested transactions share the connection and use savepoints.
+pub struct Transaction {
+    pub pool: Arc<SqlitePool>,
+    connection: Arc<PooledConnection>,
+    depth: usize,
+}
+
+impl Transaction {
+    /// Start a transaction, or a savepoint inside of the transaction that is already in progress
+    pub fn begin(pool: Arc<SqlitePool>, outer: Option<&Transaction>) -> Result<Self, ServError> {
+        let output = match outer {
+            Some(outer) => Self { pool, connection: outer.connection.clone(), depth: outer.depth + 1 },
+            None => Self { connection: Arc::new(pool.get()?), pool, depth: 0 },
+        };
+
+        output.execute(&match output.depth {
+            0 => "BEGIN;".to_owned(),
+            n => format!("SAVEPOINT serv_{};", n),
+        })?;
+
+        Ok(output)
+    }
+
+    pub fn connection(&self) -> &sqlite::ConnectionThreadSafe {
+        &self.connection
+    }
+
+    pub fn commit(&self) -> Result<(), ServError> {
+        self.execute(&match self.depth {
+            0 => "COMMIT;".to_owned(),
+            n => format!("RELEASE serv_{};", n),
+        })
+    }
+
+    pub fn rollback(&self) -> Result<(), ServError> {
+        self.execute(&match self.depth {
+            0 => "ROLLBACK;".to_owned(),
+            n => format!("ROLLBACK TO serv_{n}; RELEASE serv_{n};", n = n),
+        })
+    }
+
+    /// Commit if the result is Ok and roll back otherwise, including when the commit fails.
+    /// The error returned is the one that ended the transaction, not any from rolling back.
+    pub fn finish<T>(&self, result: Result<T, ServError>) -> Result<T, ServError> {
+        let result = result.and_then(|output| self.commit().map(|_| output));
+        if result.is_err() { self.abort() };
+        result
+    }
+
+    /// Roll back, closing the connection if that fails so that it doesn't go back to the pool
+    /// in the middle of a transaction
+    pub fn abort(&self) {
+        if self.rollback().is_err() {
+            self.connection.discard();
+        }
+    }
+
+    fn execute(&self, statement: &str) -> Result<(), ServError> {
+        Ok(self.connection.execute(statement)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database file that is deleted along with its journal once the test is done
+    struct TempDatabase(String);
+
+    impl TempDatabase {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("serv-{}-{}.db", name, std::process::id()));
+            Self(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                _ = std::fs::remove_file(format!("{}{}", self.0, suffix));
+            }
+        }
+    }
+
+    #[test]
+    fn connections_are_returned_when_dropped() {
+        let database = TempDatabase::new("pool-returned");
+        let pool = Arc::new(SqlitePool::open(&database.0, PoolOptions { size: 2, ..Default::default() }).unwrap());
+
+        let first = pool.get().unwrap();
+        let second = pool.get().unwrap();
+        assert_eq!(pool.lock().open, 2);
+        assert!(pool.lock().idle.is_empty());
+
+        drop(first);
+        drop(second);
+        assert_eq!(pool.lock().idle.len(), 2);
+
+        // taking connections again reuses them instead of opening more
+        let _third = pool.get().unwrap();
+        assert_eq!(pool.lock().open, 2);
+    }
+
+    #[test]
+    fn discarded_connections_are_closed() {
+        let database = TempDatabase::new("pool-discarded");
+        let pool = Arc::new(SqlitePool::open(&database.0, PoolOptions { size: 2, ..Default::default() }).unwrap());
+
+        let connection = pool.get().unwrap();
+        connection.discard();
+        drop(connection);
+        assert_eq!(pool.lock().open, 0);
+        assert!(pool.lock().idle.is_empty());
+    }
+
+    #[test]
+    fn waits_for_a_connection_when_all_are_busy() {
+        let database = TempDatabase::new("pool-waits");
+        let pool = Arc::new(SqlitePool::open(&database.0, PoolOptions { size: 1, ..Default::default() }).unwrap());
+
+        let busy = pool.get().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let waiting = {
+            let pool = pool.clone();
+            std::thread::spawn(move || {
+                let connection = pool.get().unwrap();
+                sender.send(()).unwrap();
+                drop(connection);
+            })
+        };
+
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(busy);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiting.join().unwrap();
+    }
+
+    #[test]
+    fn memory_databases_share_one_connection() {
+        let pool = Arc::new(SqlitePool::open(":memory:", PoolOptions { size: 8, ..Default::default() }).unwrap());
+        pool.get().unwrap().execute("CREATE TABLE t (x); INSERT INTO t VALUES (1);").unwrap();
+
+        // a second connection would be a different, empty database
+        assert!(pool.get().unwrap().execute("SELECT x FROM t;").is_ok());
+        assert_eq!(pool.lock().open, 1);
+        assert!(pool.connect_dedicated().unwrap().is_none());
+    }
+
+    #[test]
+    fn readonly_pools_refuse_writes() {
+        let database = TempDatabase::new("pool-readonly");
+        let writable = Arc::new(SqlitePool::open(&database.0, PoolOptions::default()).unwrap());
+        writable.get().unwrap().execute("CREATE TABLE t (x);").unwrap();
+
+        let pool = Arc::new(SqlitePool::open(&database.0, PoolOptions { readonly: true, ..Default::default() }).unwrap());
+        assert!(pool.get().unwrap().execute("INSERT INTO t VALUES (1);").is_err());
+    }
+
+    #[test]
+    fn missing_databases_are_reported_on_open() {
+        let options = PoolOptions { readonly: true, ..Default::default() };
+        assert!(SqlitePool::open("/nonexistent/serv/test.db", options).is_err());
+    }
+}
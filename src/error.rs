@@ -15,6 +15,15 @@ pub enum ServError {
     Fmt(std::fmt::Error),
     MissingLabel(crate::engine::dictionary::Label),
 
+    /// an error reported by sqlite, with its result code
+    Sqlite { code: Option<isize>, message: String },
+
+    /// a client made too many requests, and should retry after this many seconds
+    RateLimited(u64),
+
+    /// a request needs to be authenticated, with the challenge to send in `WWW-Authenticate`
+    Unauthorized(String),
+
     UnexpectedType(ServType, ServType),
     InsertWithEmptyAddress,
     InsertIntoInvalidType,
@@ -29,6 +38,19 @@ impl ServError {
 	pub fn expected_type<T: Into<ServType>>(expected: ServType, actual: T) -> Self {
     	Self::UnexpectedType(expected, actual.into())
 	}
+
+	/// The http status code of a response generated from this error
+	pub fn status(&self) -> u16 {
+    	match self {
+        	Self::General(code, _) => *code,
+
+        	// the low byte of an extended result code is the primary code, 19 is SQLITE_CONSTRAINT
+        	Self::Sqlite { code: Some(code), .. } if code & 0xff == 19 => 409,
+        	Self::RateLimited(_) => 429,
+        	Self::Unauthorized(_) => 401,
+        	_ => 500,
+    	}
+	}
 }
 
 impl std::fmt::Display for ServError {
@@ -38,6 +60,10 @@ impl std::fmt::Display for ServError {
             Self::Io(err) => write!(f, "io error: {}", err),
             Self::Fmt(err) => write!(f, "fmt error: {}", err),
             Self::MissingLabel(label) => write!(f, "missing label {}", label),
+            Self::Sqlite { code: Some(code), message } => write!(f, "sqlite error {}: {}", code, message),
+            Self::Sqlite { code: None, message } => write!(f, "sqlite error: {}", message),
+            Self::RateLimited(seconds) => write!(f, "too many requests, retry after {} seconds", seconds),
+            Self::Unauthorized(_) => f.write_str("unauthorized"),
 
             Self::UnexpectedType(expected, actual) => write!(f, "expected type {}, found {}", expected, actual),
             Self::InsertWithEmptyAddress => f.write_str("empty address"),
@@ -71,6 +97,21 @@ impl From<std::fmt::Error> for ServError {
     }
 }
 
+impl From<sqlite::Error> for ServError {
+    fn from(input: sqlite::Error) -> Self {
+        Self::Sqlite {
+            code: input.code,
+            message: input.message.unwrap_or_else(|| "unknown error".to_owned()),
+        }
+    }
+}
+
+impl From<hyper::http::Error> for ServError {
+    fn from(input: hyper::http::Error) -> Self {
+        Self::new(500, &format!("invalid response: {}", input))
+    }
+}
+
 impl From<ParseError> for ServError {
     fn from(input: ParseError) -> Self {
         Self::Todo
@@ -0,0 +1,135 @@
+//! http basic and bearer token authentication
+
+use crate::{ServValue, ServResult, ServError, Stack, ServFn, ServModule, ServType};
+use crate::value::ServList;
+use crate::engine;
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::Engine;
+use subtle::ConstantTimeEq;
+
+/// The credentials in the Authorization header of the current request, if it uses a given scheme
+fn get_credentials(scheme: &str, scope: &Stack) -> Option<String> {
+    let header = scope.get_request()?.headers.get("Authorization")?.to_str().ok()?;
+    let (given, credentials) = header.trim().split_once(' ')?;
+    given.eq_ignore_ascii_case(scheme).then(|| credentials.trim().to_owned())
+}
+
+/// Check a password against a bcrypt or argon2 hash, depending on which one the hash is
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        let Ok(parsed) = PasswordHash::new(hash) else { return false };
+        return Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok()
+    }
+
+    bcrypt::verify(password, hash).unwrap_or(false)
+}
+
+/// `auth.basic (alice = {$$2b$$12$$...}) ...` asks the browser for a username and password, and
+/// evaluates the rest of the expression with the username bound to `auth.user` once they match one
+/// of the bcrypt or argon2 hashes in the table
+fn auth_basic(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let unauthorized = || ServError::Unauthorized("Basic realm=\"serv\", charset=\"UTF-8\"".to_owned());
+
+    let users = match engine::resolve(input.pop()?, None, scope)? {
+        ServValue::Table(t) => t,
+        otherwise => return Err(ServError::expected_type(ServType::Table, otherwise)),
+    };
+
+    let credentials = get_credentials("Basic", scope).ok_or_else(unauthorized)?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(credentials).map_err(|_| unauthorized())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| unauthorized())?;
+    let (user, password) = decoded.split_once(':').ok_or_else(unauthorized)?;
+
+    // unknown users are checked against someone else's hash, so that they take just as long to
+    // turn away, and can't be told apart from known users with a wrong password by timing
+    let known = users.get(user);
+    let hash = known.or_else(|| users.values().next()).map(|h| h.to_string()).ok_or_else(unauthorized)?;
+    if !verify_password(password, &hash) || known.is_none() {
+        return Err(unauthorized())
+    }
+
+    scope.insert("auth.user", user.to_owned().into())?;
+    input.eval(scope)
+}
+
+/// Compare a token with a known one in constant time, so that timing doesn't show how much of
+/// a guess was right
+fn token_matches(known: &str, token: &str) -> bool {
+    known.as_bytes().ct_eq(token.as_bytes()).into()
+}
+
+/// The value paired with the token among a set of known tokens, comparing every one of them
+fn match_token(known: impl Iterator<Item = (String, ServValue)>, token: &str) -> Option<ServValue> {
+    known.fold(None, |found, (k, v)| if token_matches(&k, token) { Some(v) } else { found })
+}
+
+/// Split a row returned by a query into its `token` column and the user, which is the rest of
+/// the row, or the only other column in it
+fn token_row(row: ServValue) -> Result<(String, ServValue), ServError> {
+    let ServValue::Table(mut row) = row else {
+        return Err(ServError::new(500, &format!("auth.bearer expects rows from a query, not a {}", ServType::from(&row))))
+    };
+
+    let Some(token) = row.remove("token") else {
+        return Err(ServError::new(500, "auth.bearer expects the rows from a query to have a token column"))
+    };
+
+    let user = match row.len() {
+        0 => token.clone(),
+        1 => row.into_values().next().unwrap_or_default(),
+        _ => ServValue::Table(row),
+    };
+
+    Ok((token.to_string(), user))
+}
+
+/// Find the user a token belongs to, from the result of the lookup given to `auth.bearer`.
+/// Anything that isn't clearly a match is an error rather than a user, so that a mistake in the
+/// lookup can't let every token in.
+fn find_user(lookup: ServValue, token: &str) -> Result<Option<ServValue>, ServError> {
+    Ok(match lookup {
+        ServValue::Table(t) => match_token(t.into_iter(), token),
+
+        // rows returned by a query are checked against their token column as well, so that a
+        // query that doesn't filter on the token can't let every request in
+        ServValue::List(rows) => match rows.clone().next() {
+            None => None,
+            Some(ServValue::Table(_)) => match_token(rows.map(token_row).collect::<Result<Vec<_>, _>>()?.into_iter(), token),
+            Some(ServValue::Text(_)) => match_token(rows.map(|t| (t.to_string(), t)), token),
+            Some(otherwise) => return Err(ServError::new(500, &format!("auth.bearer can't find a user in a list of {}", ServType::from(&otherwise)))),
+        },
+
+        ServValue::Text(t) => match_token(std::iter::once((t.as_str()?.to_owned(), ServValue::Text(t))), token),
+        ServValue::None | ServValue::Bool(false) => None,
+        otherwise => return Err(ServError::new(500, &format!("auth.bearer expects a token, a list of tokens, a table of tokens to users, or rows from a query, not a {}", ServType::from(&otherwise)))),
+    })
+}
+
+/// `auth.bearer lookup ...` checks the token in a request's Authorization header, and evaluates
+/// the rest of the expression with the user it belongs to bound to `auth.user`. The lookup is
+/// evaluated with the token bound to `auth.token`, and can be a valid token, a list of them, a
+/// table of tokens to users, or a query that returns the user along with its token.
+fn auth_bearer(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let lookup = input.pop()?;
+    let Some(token) = get_credentials("Bearer", scope).filter(|t| !t.is_empty()) else {
+        return Err(ServError::Unauthorized("Bearer".to_owned()))
+    };
+
+    scope.insert("auth.token", token.clone().into())?;
+    let lookup = engine::resolve(lookup, None, scope)?;
+
+    let Some(user) = find_user(lookup, &token)? else {
+        return Err(ServError::Unauthorized("Bearer error=\"invalid_token\"".to_owned()))
+    };
+
+    scope.insert("auth.user", user)?;
+    input.eval(scope)
+}
+
+pub fn get_module() -> ServModule {
+    let mut output = ServModule::empty();
+	output.insert("auth.basic",  ServFn::Meta(auth_basic).into());
+	output.insert("auth.bearer", ServFn::Meta(auth_bearer).into());
+	output
+}
@@ -0,0 +1,137 @@
+//! functions for server sent events and websockets
+
+use crate::{ServValue, ServResult, ServError, Stack, ServFn, ServModule};
+use crate::value::{ServList, Serializer, DefaultSerializer};
+
+use std::collections::HashMap;
+use std::sync::{Mutex, LazyLock};
+use tokio::sync::broadcast;
+
+/// Named channels that routes can publish to, and event streams can subscribe to
+static CHANNELS: LazyLock<Mutex<HashMap<String, broadcast::Sender<String>>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn lock_channels() -> std::sync::MutexGuard<'static, HashMap<String, broadcast::Sender<String>>> {
+    CHANNELS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A receiver for a channel. Channels only exist while something is subscribed to them, so that
+/// connections to routes like `/room/{name}` can't fill up memory with channels nobody uses.
+pub struct Subscription {
+    name: Option<String>,
+    receiver: broadcast::Receiver<String>,
+}
+
+impl Subscription {
+    pub async fn recv(&mut self) -> Result<String, broadcast::error::RecvError> {
+        self.receiver.recv().await
+    }
+}
+
+/// A receiver that isn't for a named channel, and so doesn't need cleaning up
+impl From<broadcast::Receiver<String>> for Subscription {
+    fn from(receiver: broadcast::Receiver<String>) -> Self {
+        Self { name: None, receiver }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let Some(ref name) = self.name else { return };
+        let mut channels = lock_channels();
+
+        // new subscribers are added while holding the lock, so this receiver is the last one
+        if channels.get(name).is_some_and(|c| c.receiver_count() <= 1) {
+            channels.remove(name);
+        }
+    }
+}
+
+/// Subscribe to the channel with the given name, creating it if it doesn't exist yet
+pub fn subscribe(name: &str) -> Subscription {
+    let mut channels = lock_channels();
+    let receiver = channels.entry(name.to_owned())
+        .or_insert_with(|| broadcast::channel(64).0)
+        .subscribe();
+
+    Subscription { name: Some(name.to_owned()), receiver }
+}
+
+/// Send data to everything subscribed to a channel, if anything is
+pub fn send(name: &str, data: String) {
+    if let Some(channel) = lock_channels().get(name) {
+        // sending only fails when every receiver has just been dropped, which is fine
+        _ = channel.send(data);
+    }
+}
+
+/// Send a value to every stream subscribed to a channel, returning the value
+fn publish(arg: ServValue, input: ServValue, scope: &Stack) -> ServResult {
+    let name = arg.call(None, scope)?.to_string();
+
+    let mut data = String::new();
+    DefaultSerializer(scope).write(input.clone(), &mut data)?;
+
+    send(&name, data);
+    Ok(input)
+}
+
+/// Turn the current route into an event stream of everything published to a channel. The rest
+/// of the expression, if it isn't empty, is sent as the first event.
+fn sse(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let name = input.pop()?.call(None, scope)?.to_string();
+    scope.insert("res.sse.channel", ServValue::from(name))?;
+    input.eval(scope)
+}
+
+/// Turn the current route into an event stream that evaluates the rest of the expression
+/// every n seconds
+fn sse_every(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let seconds = input.pop()?.call(None, scope)?.expect_int()?;
+    if seconds <= 0 {
+        return Err(ServError::new(500, "sse.every expects a positive number of seconds"))
+    }
+
+    scope.insert("res.sse.every", ServValue::Int(seconds))?;
+    scope.insert("res.sse.expr", input.as_expr())?;
+    Ok(ServValue::None)
+}
+
+/// Subscribe the websocket that sent the current message to another channel, so that it
+/// receives everything broadcast there as well as on its own route
+fn ws_join(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let name = input.pop()?.call(None, scope)?.to_string();
+    scope.insert("res.ws.join", ServValue::from(name))?;
+    input.eval(scope)
+}
+
+pub fn get_module() -> ServModule {
+    let mut output = ServModule::empty();
+	output.insert("sse",       ServFn::Meta(sse).into());
+	output.insert("sse.every", ServFn::Meta(sse_every).into());
+	output.insert("publish",   ServFn::ArgFn(publish).into());
+
+	output.insert("ws.broadcast", ServFn::ArgFn(publish).into());
+	output.insert("ws.join",      ServFn::Meta(ws_join).into());
+	output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_are_removed_with_their_last_subscriber() {
+        let first = subscribe("test-cleanup");
+        let second = subscribe("test-cleanup");
+
+        drop(first);
+        assert!(lock_channels().contains_key("test-cleanup"));
+
+        drop(second);
+        assert!(!lock_channels().contains_key("test-cleanup"));
+
+        // publishing to a channel nobody is subscribed to doesn't create it
+        send("test-cleanup", "hello".to_owned());
+        assert!(!lock_channels().contains_key("test-cleanup"));
+    }
+}
@@ -0,0 +1,546 @@
+//! server side syntax highlighting, which wraps tokens of code in spans with `hl-*` classes
+
+use crate::{ServValue, ServResult, Stack, ServFn, ServModule, ServString};
+use crate::template::escape_html;
+
+/// The kinds of token that get a class, and can be styled with `csslib.highlight`
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+    Keyword,
+    Literal,
+    String,
+    Number,
+    Comment,
+    Function,
+    Variable,
+    Tag,
+    Attr,
+}
+
+impl Class {
+    fn name(self) -> &'static str {
+        match self {
+            Class::Keyword  => "hl-keyword",
+            Class::Literal  => "hl-literal",
+            Class::String   => "hl-string",
+            Class::Number   => "hl-number",
+            Class::Comment  => "hl-comment",
+            Class::Function => "hl-function",
+            Class::Variable => "hl-variable",
+            Class::Tag      => "hl-tag",
+            Class::Attr     => "hl-attr",
+        }
+    }
+}
+
+/// How to split the code of a c-like language into tokens
+struct Language {
+    names: &'static [&'static str],
+    keywords: &'static [&'static str],
+    literals: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+
+    /// Whether block comments can be nested, like `/* a /* b */ c */` in rust
+    nested_comments: bool,
+    quotes: &'static [char],
+
+    /// Whether `$name` is a variable, like in shell scripts
+    variables: bool,
+    ignore_case: bool,
+}
+
+static LANGUAGES: &[Language] = &[
+    Language {
+        names: &["rust", "rs"],
+        keywords: &["as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "type", "unsafe", "use", "where", "while"],
+        literals: &["true", "false", "None", "Some", "Ok", "Err"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        nested_comments: true,
+        quotes: &['"'],
+        variables: false,
+        ignore_case: false,
+    },
+    Language {
+        names: &["javascript", "js", "typescript", "ts", "jsx", "tsx"],
+        keywords: &["async", "await", "break", "case", "catch", "class", "const", "continue", "default", "delete", "do", "else", "export", "extends", "finally", "for", "from", "function", "if", "import", "in", "instanceof", "interface", "let", "new", "of", "return", "static", "switch", "this", "throw", "try", "type", "typeof", "var", "void", "while", "yield"],
+        literals: &["true", "false", "null", "undefined", "NaN"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        nested_comments: false,
+        quotes: &['"', '\'', '`'],
+        variables: false,
+        ignore_case: false,
+    },
+    Language {
+        names: &["python", "py"],
+        keywords: &["and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with", "yield"],
+        literals: &["True", "False", "None"],
+        line_comments: &["#"],
+        block_comment: None,
+        nested_comments: false,
+        quotes: &['"', '\''],
+        variables: false,
+        ignore_case: false,
+    },
+    Language {
+        names: &["sql", "sqlite"],
+        keywords: &["add", "all", "alter", "and", "as", "asc", "begin", "between", "by", "case", "commit", "create", "delete", "desc", "distinct", "drop", "else", "end", "exists", "from", "group", "having", "if", "in", "index", "inner", "insert", "into", "is", "join", "key", "left", "like", "limit", "not", "offset", "on", "or", "order", "primary", "references", "returning", "rollback", "select", "set", "table", "then", "union", "unique", "update", "values", "view", "when", "where", "with"],
+        literals: &["null", "true", "false", "current_timestamp"],
+        line_comments: &["--"],
+        block_comment: Some(("/*", "*/")),
+        nested_comments: false,
+        quotes: &['\'', '"'],
+        variables: false,
+        ignore_case: true,
+    },
+    Language {
+        names: &["bash", "sh", "shell", "zsh"],
+        keywords: &["case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if", "in", "local", "return", "then", "until", "while"],
+        literals: &["true", "false"],
+        line_comments: &["#"],
+        block_comment: None,
+        nested_comments: false,
+        quotes: &['"', '\''],
+        variables: true,
+        ignore_case: false,
+    },
+    Language {
+        names: &["css", "scss"],
+        keywords: &["@media", "@import", "@keyframes", "@font-face", "!important"],
+        literals: &[],
+        line_comments: &[],
+        block_comment: Some(("/*", "*/")),
+        nested_comments: false,
+        quotes: &['"', '\''],
+        variables: false,
+        ignore_case: false,
+    },
+    Language {
+        names: &["json"],
+        keywords: &[],
+        literals: &["true", "false", "null"],
+        line_comments: &[],
+        block_comment: None,
+        nested_comments: false,
+        quotes: &['"'],
+        variables: false,
+        ignore_case: false,
+    },
+];
+
+/// Collects highlighted html, escaping everything written to it
+struct Output(String);
+
+impl Output {
+    fn plain(&mut self, text: &str) {
+        self.0.push_str(&escape_html(text));
+    }
+
+    fn span(&mut self, class: Class, text: &str) {
+        if text.is_empty() { return };
+        self.0.push_str(&format!("<span class=\"{}\">{}</span>", class.name(), escape_html(text)));
+    }
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The length of the text up to and including `end`, or all of it if `end` isn't found
+fn until(text: &str, skip: usize, end: &str) -> usize {
+    text[skip..].find(end).map(|i| skip + i + end.len()).unwrap_or(text.len())
+}
+
+/// The length of a block comment at the start of the text, counting the comments inside of it
+/// if they can be nested, or all of the text if it isn't closed
+fn block_comment(text: &str, open: &str, close: &str, nested: bool) -> usize {
+    if !nested { return until(text, open.len(), close) };
+
+    let mut depth = 0;
+    let mut i = 0;
+    while i < text.len() {
+        if text[i..].starts_with(open) {
+            depth += 1;
+            i += open.len();
+        } else if text[i..].starts_with(close) {
+            depth -= 1;
+            i += close.len();
+            if depth == 0 { return i };
+        } else {
+            i += text[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+
+    text.len()
+}
+
+/// The length of a quoted string at the start of the text, allowing escaped quotes inside of it
+fn quoted(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == quote => return i + c.len_utf8(),
+            _ => {},
+        }
+    }
+
+    text.len()
+}
+
+fn take_while(text: &str, f: impl Fn(char) -> bool) -> usize {
+    text.char_indices().find(|(_, c)| !f(*c)).map(|(i, _)| i).unwrap_or(text.len())
+}
+
+fn highlight_language(code: &str, language: &Language, output: &mut Output) {
+    let mut rest = code;
+    let mut previous = ' ';
+
+    while let Some(c) = rest.chars().next() {
+        let (class, len) = if language.line_comments.iter().any(|m| rest.starts_with(*m)) {
+            (Some(Class::Comment), rest.find('\n').unwrap_or(rest.len()))
+        } else if let Some((open, close)) = language.block_comment.filter(|(open, _)| rest.starts_with(*open)) {
+            (Some(Class::Comment), block_comment(rest, open, close, language.nested_comments))
+        } else if language.quotes.contains(&c) {
+            let len = quoted(rest, c);
+            let is_key = language.names.contains(&"json") && rest[len..].trim_start().starts_with(':');
+            (Some(if is_key { Class::Attr } else { Class::String }), len)
+        } else if language.variables && c == '$' {
+            (Some(Class::Variable), 1 + take_while(&rest[1..], is_ident))
+        } else if c.is_ascii_digit() && !is_ident(previous) {
+            (Some(Class::Number), take_while(rest, |c| is_ident(c) || c == '.'))
+        } else if is_ident(c) || c == '@' || c == '!' {
+            // css properties and values are words joined by dashes, ie. `font-family`
+            let dashes = language.names.contains(&"css");
+            let len = c.len_utf8() + take_while(&rest[c.len_utf8()..], |c| is_ident(c) || (dashes && c == '-'));
+            let word = &rest[..len];
+            let matches = |words: &[&str]| match language.ignore_case {
+                true  => words.iter().any(|w| w.eq_ignore_ascii_case(word)),
+                false => words.contains(&word),
+            };
+
+            let next = rest[len..].chars().next();
+            let class = match () {
+                _ if matches(language.keywords) => Some(Class::Keyword),
+                _ if matches(language.literals) => Some(Class::Literal),
+                _ if is_ident(c) && (next == Some('(') || next == Some('!')) => Some(Class::Function),
+                _ => None,
+            };
+
+            (class, len)
+        } else {
+            (None, c.len_utf8())
+        };
+
+        match class {
+            Some(class) => output.span(class, &rest[..len]),
+            None => output.plain(&rest[..len]),
+        }
+
+        previous = rest[..len].chars().last().unwrap_or(' ');
+        rest = &rest[len..];
+    }
+}
+
+/// Html and xml, with tag names, attribute names and attribute values highlighted
+fn highlight_markup(code: &str, output: &mut Output) {
+    let mut rest = code;
+
+    while !rest.is_empty() {
+        if rest.starts_with("<!--") {
+            let len = until(rest, 4, "-->");
+            output.span(Class::Comment, &rest[..len]);
+            rest = &rest[len..];
+            continue
+        }
+
+        if !rest.starts_with('<') {
+            let len = rest.find('<').unwrap_or(rest.len());
+            output.plain(&rest[..len]);
+            rest = &rest[len..];
+            continue
+        }
+
+        let open = 1 + take_while(&rest[1..], |c| c == '/' || c == '!' || c == '?');
+        let name = open + take_while(&rest[open..], |c| is_ident(c) || c == '-' || c == ':');
+        output.span(Class::Tag, &rest[..name]);
+        rest = &rest[name..];
+
+        // attributes, until the end of the tag
+        while let Some(c) = rest.chars().next() {
+            let len = match c {
+                '>' => { output.span(Class::Tag, ">"); rest = &rest[1..]; break },
+                '/' | '?' if rest[1..].starts_with('>') => { output.span(Class::Tag, &rest[..2]); rest = &rest[2..]; break },
+                '"' | '\'' => { let len = quoted(rest, c); output.span(Class::String, &rest[..len]); len },
+                c if is_ident(c) => {
+                    let len = take_while(rest, |c| is_ident(c) || c == '-' || c == ':');
+                    output.span(Class::Attr, &rest[..len]);
+                    len
+                },
+                c => { output.plain(&rest[..c.len_utf8()]); c.len_utf8() },
+            };
+
+            rest = &rest[len..];
+        }
+    }
+}
+
+/// The length of a serv string at the start of the text, including nested pairs of brackets
+fn serv_string(text: &str) -> usize {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 1 => return i + 1,
+            '}' => depth -= 1,
+            _ => {},
+        }
+    }
+
+    text.len()
+}
+
+/// Serv itself, with routes, definitions, strings and the variables inside of them
+fn highlight_serv(code: &str, output: &mut Output) {
+    let mut rest = code;
+    let mut line_start = true;
+
+    while let Some(c) = rest.chars().next() {
+        let (class, len) = match c {
+            '#' => (Some(Class::Comment), rest.find('\n').unwrap_or(rest.len())),
+            '{' => {
+                let len = serv_string(rest);
+                highlight_serv_string(&rest[..len], output);
+                rest = &rest[len..];
+                line_start = false;
+                continue
+            },
+            '/' if line_start => (Some(Class::Tag), take_while(rest, |c| !c.is_whitespace())),
+            '@' => (Some(Class::Keyword), 1 + take_while(&rest[1..], |c| is_ident(c) || c == '.')),
+            '=' if rest.starts_with("=>") => (Some(Class::Keyword), 2),
+            c if c.is_ascii_digit() => (Some(Class::Number), take_while(rest, |c| c.is_ascii_digit() || c == '.')),
+            c if is_ident(c) => {
+                let len = take_while(rest, |c| is_ident(c) || c == '.' || c == '-');
+                let defines = line_start && rest[len..].trim_start().starts_with('=') && !rest[len..].trim_start().starts_with("=>");
+                let class = match &rest[..len] {
+                    "ws" if line_start => Some(Class::Keyword),
+                    "true" | "false" => Some(Class::Literal),
+                    _ if defines => Some(Class::Function),
+                    _ => None,
+                };
+
+                (class, len)
+            },
+            c => (None, c.len_utf8()),
+        };
+
+        match class {
+            Some(class) => output.span(class, &rest[..len]),
+            None => output.plain(&rest[..len]),
+        }
+
+        line_start = c == '\n';
+        rest = &rest[len..];
+    }
+}
+
+/// A serv string, with `$name` and `$(...)` interpolations highlighted as variables
+fn highlight_serv_string(text: &str, output: &mut Output) {
+    let mut rest = text;
+
+    while !rest.is_empty() {
+        let Some(start) = rest.find('$') else {
+            output.span(Class::String, rest);
+            return
+        };
+
+        output.span(Class::String, &rest[..start]);
+        let after = &rest[start + 1..];
+
+        let len = match after.chars().next() {
+            Some('(') => {
+                let mut depth = 0;
+                after.char_indices()
+                    .find(|(_, c)| { match c { '(' => depth += 1, ')' => depth -= 1, _ => {} }; depth == 0 })
+                    .map(|(i, _)| i + 1)
+                    .unwrap_or(after.len())
+            },
+            _ => take_while(after, |c| is_ident(c) || c == '.' || c == '*'),
+        };
+
+        output.span(Class::Variable, &rest[start..start + 1 + len]);
+        rest = &after[len..];
+    }
+}
+
+/// Highlight code as html, or only escape it if the language isn't one that is known
+pub(super) fn highlight(code: &str, language: &str) -> String {
+    let mut output = Output(String::with_capacity(code.len() * 2));
+    let language = language.trim().to_lowercase();
+
+    match language.as_str() {
+        "serv" => highlight_serv(code, &mut output),
+        "html" | "xml" | "svg" => highlight_markup(code, &mut output),
+        name => match LANGUAGES.iter().find(|l| l.names.contains(&name)) {
+            Some(language) => highlight_language(code, language, &mut output),
+            None => output.plain(code),
+        },
+    }
+
+    output.0
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&amp;", "&")
+}
+
+/// Highlight the fenced code blocks in html rendered from markdown,
+/// which look like `<pre><code class="language-rust">...</code></pre>`
+pub(super) fn highlight_code_blocks(html: &str) -> String {
+    const OPEN: &str = "<pre><code class=\"language-";
+    const CLOSE: &str = "</code></pre>";
+
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find(OPEN) {
+        let block = &rest[start..];
+        let (Some(quote), Some(end)) = (block[OPEN.len()..].find('"'), block.find(CLOSE)) else { break };
+
+        let language = &block[OPEN.len()..OPEN.len() + quote];
+        let Some(body) = block[..end].find('>').map(|i| &block[i + 1..end]) else { break };
+
+        output.push_str(&rest[..start]);
+        output.push_str(&block[..block.find('>').unwrap_or(0) + 1]);
+        output.push_str(&highlight(&unescape_html(body), language));
+        output.push_str(CLOSE);
+
+        rest = &block[end + CLOSE.len()..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// `highlight {rust} {fn main() {}}` returns the code as highlighted html, in a `<pre><code>` block
+fn highlight_fn(arg: ServValue, input: ServValue, scope: &Stack) -> ServResult {
+    let language = arg.call(None, scope)?.to_string();
+    let html = format!(
+        "<pre><code class=\"language-{}\">{}</code></pre>",
+        escape_html(&language), highlight(&input.to_string(), &language)
+    );
+
+    Ok(ServString::from(html).mark_safe().as_value())
+}
+
+const STYLESHEET: &str = "\
+.hl-keyword  { color: #a626a4; }
+.hl-literal  { color: #986801; }
+.hl-string   { color: #50a14f; }
+.hl-number   { color: #986801; }
+.hl-comment  { color: #a0a1a7; font-style: italic; }
+.hl-function { color: #4078f2; }
+.hl-variable { color: #e45649; }
+.hl-tag      { color: #e45649; }
+.hl-attr     { color: #c18401; }
+@media (prefers-color-scheme: dark) {
+    .hl-keyword  { color: #c678dd; }
+    .hl-literal  { color: #d19a66; }
+    .hl-string   { color: #98c379; }
+    .hl-number   { color: #d19a66; }
+    .hl-comment  { color: #7f848e; }
+    .hl-function { color: #61afef; }
+    .hl-variable { color: #e06c75; }
+    .hl-tag      { color: #e06c75; }
+    .hl-attr     { color: #e5c07b; }
+}
+";
+
+/// A stylesheet with colors for every `hl-*` class, for light and dark color schemes
+fn highlight_css(input: ServValue, scope: &Stack) -> ServResult {
+    let mut output = ServString::from(STYLESHEET);
+    output.mime = Some("text/css");
+    Ok(output.as_value())
+}
+
+pub fn get_module() -> ServModule {
+    let mut output = ServModule::empty();
+	output.insert("highlight",        ServFn::ArgFn(highlight_fn).into());
+	output.insert("csslib.highlight", ServFn::Core(highlight_css).into());
+	output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(class: &str, text: &str) -> String {
+        format!("<span class=\"hl-{}\">{}</span>", class, text)
+    }
+
+    #[test]
+    fn rust() {
+        assert_eq!(highlight(r#"let s = "a \"b\" c";"#, "rust"), format!("{} s = {};", span("keyword", "let"), span("string", "&quot;a \\&quot;b\\&quot; c&quot;")));
+        assert_eq!(highlight("/* a /* b */ c */ x", "rs"), format!("{} x", span("comment", "/* a /* b */ c */")));
+        assert_eq!(highlight("x /* open", "rust"), format!("x {}", span("comment", "/* open")));
+    }
+
+    #[test]
+    fn javascript() {
+        assert_eq!(highlight(r"'it\'s' `a${b}`", "js"), format!("{} {}", span("string", "&#39;it\\&#39;s&#39;"), span("string", "`a${b}`")));
+        assert_eq!(highlight("/* a /* b */ c", "javascript"), format!("{} c", span("comment", "/* a /* b */")));
+        assert_eq!(highlight("f(); // <b>", "ts"), format!("{}(); {}", span("function", "f"), span("comment", "// &lt;b&gt;")));
+    }
+
+    #[test]
+    fn python() {
+        assert_eq!(highlight(r#"print("a\"b") # done"#, "python"), format!("{}({}) {}", span("function", "print"), span("string", "&quot;a\\&quot;b&quot;"), span("comment", "# done")));
+        assert_eq!(highlight("'open", "py"), span("string", "&#39;open"));
+    }
+
+    #[test]
+    fn sql() {
+        assert_eq!(highlight("SELECT 'it''s' /* a /* b */ x", "sql"), format!("{} {}{} {} x", span("keyword", "SELECT"), span("string", "&#39;it&#39;"), span("string", "&#39;s&#39;"), span("comment", "/* a /* b */")));
+        assert_eq!(highlight("x -- rest", "sqlite"), format!("x {}", span("comment", "-- rest")));
+    }
+
+    #[test]
+    fn bash() {
+        assert_eq!(highlight(r#"echo "a \"$b\"" # c"#, "sh"), format!("echo {} {}", span("string", "&quot;a \\&quot;$b\\&quot;&quot;"), span("comment", "# c")));
+        assert_eq!(highlight("if $x; then", "bash"), format!("{} {}; {}", span("keyword", "if"), span("variable", "$x"), span("keyword", "then")));
+    }
+
+    #[test]
+    fn css() {
+        assert_eq!(highlight(r#"a { content: "\"" } /* open"#, "css"), format!("a {{ content: {} }} {}", span("string", "&quot;\\&quot;&quot;"), span("comment", "/* open")));
+        assert_eq!(highlight("@media x", "scss"), format!("{} x", span("keyword", "@media")));
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(highlight(r#"{"a\"": true}"#, "json"), format!("{{{}: {}}}", span("attr", "&quot;a\\&quot;&quot;"), span("literal", "true")));
+        assert_eq!(highlight(r#"["open"#, "json"), format!("[{}", span("string", "&quot;open")));
+    }
+
+    #[test]
+    fn markup() {
+        assert_eq!(highlight(r#"<a href="x">&</a>"#, "html"), format!("{} {}={}{}&amp;{}{}", span("tag", "&lt;a"), span("attr", "href"), span("string", "&quot;x&quot;"), span("tag", "&gt;"), span("tag", "&lt;/a"), span("tag", "&gt;")));
+        assert_eq!(highlight("<!-- a <!-- b --> c", "xml"), format!("{} c", span("comment", "&lt;!-- a &lt;!-- b --&gt;")));
+        assert_eq!(highlight("<!-- open", "html"), span("comment", "&lt;!-- open"));
+    }
+
+    #[test]
+    fn serv() {
+        assert_eq!(highlight("/hi => {<b>$name</b>} # c", "serv"), format!("{} {} {}{}{} {}", span("tag", "/hi"), span("keyword", "=&gt;"), span("string", "{&lt;b&gt;"), span("variable", "$name"), span("string", "&lt;/b&gt;}"), span("comment", "# c")));
+        assert_eq!(highlight("x = {open {nested}", "serv"), format!("{} = {}", span("function", "x"), span("string", "{open {nested}")));
+    }
+
+    #[test]
+    fn unknown_language_is_escaped() {
+        assert_eq!(highlight("<b>\"x\" & 'y'</b>", "klingon"), "&lt;b&gt;&quot;x&quot; &amp; &#39;y&#39;&lt;/b&gt;");
+        assert_eq!(highlight("<b>", ""), "&lt;b&gt;");
+    }
+}
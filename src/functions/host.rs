@@ -49,10 +49,42 @@ fn get_path(input: &Path) -> Option<&str> {
     input.extension()?.to_str()
 }
 
+use std::sync::Mutex;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// Paths read by `file` while recording, so that they can be watched for changes
+static FILES_READ: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Start keeping track of every file read with the `file` function
+pub fn record_files_read() {
+    *FILES_READ.lock().unwrap_or_else(|e| e.into_inner()) = Some(HashSet::new());
+}
+
+/// Keep track of a file read by a function other than `file`, if recording
+pub(super) fn note_file_read(path: &Path) {
+    if let Some(ref mut recorded) = *FILES_READ.lock().unwrap_or_else(|e| e.into_inner()) {
+        recorded.insert(path.to_path_buf());
+    }
+}
+
+/// Return the files read since recording started or since the last call, and keep recording
+pub fn take_files_read() -> Vec<PathBuf> {
+    let mut recorded = FILES_READ.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(files) = recorded.as_mut() else { return Vec::new() };
+    files.drain().collect()
+}
+
+pub fn stop_recording_files() {
+    *FILES_READ.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
 fn read_file(input: ServValue, scope: &Stack) -> ServResult {
     let path = std::path::Path::new(input.as_str()?);
     let contents = std::fs::read(path)?;
 
+    note_file_read(path);
+
     let mut data = ServString::from_bytes(contents);
     if let Some(ext) = get_path(&path) {
         data.mime = match ext {
@@ -0,0 +1,115 @@
+//! layouts, which are templates with named blocks that pages fill in
+
+use crate::{ServValue, ServResult, ServError, Stack, ServFn, ServModule, ServString, ServType};
+use crate::value::ServList;
+use crate::engine;
+
+use std::collections::HashMap;
+
+/// Where the blocks filled in by the page being rendered are kept in scope
+const BLOCKS: &str = ":blocks";
+
+/// How the content a page gives for a block is combined with the layout's default content
+enum Fill {
+    Replace(ServValue),
+    Append(ServValue),
+    Prepend(ServValue),
+}
+
+impl Fill {
+    fn from_value(value: ServValue) -> Self {
+        let ServValue::Table(ref table) = value else { return Self::Replace(value) };
+        if table.len() != 1 { return Self::Replace(value) };
+
+        match (table.get("append"), table.get("prepend")) {
+            (Some(v), _) => Self::Append(v.clone()),
+            (_, Some(v)) => Self::Prepend(v.clone()),
+            _ => Self::Replace(value),
+        }
+    }
+}
+
+fn is_safe(value: &ServValue) -> bool {
+    match value {
+        ServValue::Text(t) => t.is_safe(),
+        ServValue::None => true,
+        _ => false,
+    }
+}
+
+/// Join the contents of a block, keeping it marked as html if every part of it is
+fn join(first: ServValue, second: ServValue) -> ServValue {
+    let safe = is_safe(&first) && is_safe(&second);
+    let text = ServString::from(format!("{}{}", text(first), text(second)));
+    ServValue::Text(if safe { text.mark_safe() } else { text })
+}
+
+fn text(value: ServValue) -> String {
+    match value {
+        ServValue::None => String::new(),
+        value => value.to_string(),
+    }
+}
+
+/// The name of a block, which is usually written as a bare word, ie. `block title`
+fn block_name(value: ServValue, scope: &mut Stack) -> Result<String, ServError> {
+    match value {
+        ServValue::Ref(ref addr) if engine::deref(addr, scope).is_err() => {
+            Ok(addr.iter().map(|label| label.to_string()).collect::<Vec<_>>().join("."))
+        },
+        otherwise => Ok(otherwise.call(None, scope)?.to_string()),
+    }
+}
+
+/// `layout base (title = {Home}, head = block.append {...}) ...` renders the `base` template
+/// with the rest of the expression bound to `*`, and with the blocks it declares filled in
+fn layout(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let base = input.pop()?;
+    let blocks = match engine::resolve(input.pop()?, None, scope)? {
+        ServValue::Table(t) => t,
+        ServValue::None => HashMap::new(),
+        otherwise => return Err(ServError::expected_type(ServType::Table, otherwise)),
+    };
+
+    let rest = input.eval(scope)?;
+
+    let mut child = scope.make_child();
+    child.insert(BLOCKS, ServValue::Table(blocks))?;
+    engine::resolve(base, Some(rest), &child)
+}
+
+/// `block name default` declares a block in a layout, which is the default content
+/// unless the page being rendered fills it in. Like any other expression in a template, the
+/// default is called with `*`, so `$(block head {})` is empty and `$(block body)` is the page.
+fn block(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let name = block_name(input.pop()?, scope)?;
+
+    let fill = match scope.get(BLOCKS) {
+        Ok(ServValue::Table(blocks)) => blocks.get(&name).cloned().map(Fill::from_value),
+        _ => None,
+    };
+
+    match fill {
+        Some(Fill::Replace(value)) => Ok(value),
+        Some(Fill::Append(value))  => Ok(join(input.eval(scope)?, value)),
+        Some(Fill::Prepend(value)) => Ok(join(value, input.eval(scope)?)),
+        None => Ok(join(input.eval(scope)?, ServValue::None)),
+    }
+}
+
+fn block_append(input: ServValue, scope: &Stack) -> ServResult {
+    Ok(ServValue::Table(HashMap::from([("append".to_owned(), input)])))
+}
+
+fn block_prepend(input: ServValue, scope: &Stack) -> ServResult {
+    Ok(ServValue::Table(HashMap::from([("prepend".to_owned(), input)])))
+}
+
+pub fn get_module() -> ServModule {
+    let mut output = ServModule::empty();
+	output.insert("layout",        ServFn::Meta(layout).into());
+	output.insert("block",         ServFn::Meta(block).into());
+	output.insert("block.append",  ServFn::Core(block_append).into());
+	output.insert("block.prepend", ServFn::Core(block_prepend).into());
+	output
+}
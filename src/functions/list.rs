@@ -116,6 +116,12 @@ fn pop(input: ServValue, scope: &Stack) -> ServResult {
 
 
 fn get(arg: ServValue, input: ServValue, scope: &Stack) -> ServResult {
+    // keys are usually written in brackets, ie. `: {title} row`
+    let arg = match arg {
+        ServValue::Func(_) => arg.call(None, scope)?,
+        arg => arg,
+    };
+
     let output = match (arg, input) {
         (ServValue::Text(ref key), ServValue::Table(mut map)) => map.remove(key.as_str()?).ok_or("key not found")?,
         (ServValue::Int(index),    ServValue::List(mut list)) => list.get(index.try_into().map_err(|e| "invalid index")?)?.clone(),
@@ -0,0 +1,472 @@
+//! markdown documents, with front matter and a table of contents
+
+use crate::{ServValue, ServResult, ServError, Stack, ServFn, ServModule, ServString};
+use crate::template::escape_html;
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Markdown is usually written by the site's authors, so html inside of it is passed through.
+/// Markdown from anywhere else can be converted with `markdown.safe`, which escapes it instead.
+fn options(trusted: bool) -> ::markdown::Options {
+    let compile_options = ::markdown::CompileOptions {
+        allow_dangerous_html: trusted,
+        allow_dangerous_protocol: trusted,
+        gfm_tagfilter: !trusted,
+        ..::markdown::CompileOptions::default()
+    };
+
+    ::markdown::Options {
+        compile: compile_options,
+        ..::markdown::Options::gfm()
+    }
+}
+
+fn convert(text: &str, trusted: bool) -> String {
+    let html = ::markdown::to_html_with_options(text, &options(trusted)).unwrap_or_else(|e| escape_html(&e.to_string()));
+    super::highlight::highlight_code_blocks(&html)
+}
+
+/// Convert markdown text into html, highlighting fenced code blocks that name their language
+pub(super) fn to_html(text: &str) -> String {
+    convert(text, true)
+}
+
+type FrontMatter = HashMap<String, ServValue>;
+
+/// Split a document into its front matter and the markdown after it. Front matter is either
+/// yaml between `---` lines or toml between `+++` lines, at the very start of the document.
+pub(super) fn split_front_matter(text: &str) -> Result<(Option<FrontMatter>, &str), ServError> {
+    let fence = match text.lines().next().map(|l| l.trim_end()) {
+        Some("---") => "---",
+        Some("+++") => "+++",
+        _ => return Ok((None, text)),
+    };
+
+    let start = text.find('\n').map(|i| i + 1).unwrap_or(text.len());
+    let mut offset = start;
+    for line in text[start..].split_inclusive('\n') {
+        if line.trim_end() == fence {
+            let front = &text[start..offset];
+            let table = if fence == "---" { parse_yaml(front)? } else { parse_toml(front)? };
+            return Ok((Some(table), &text[offset + line.len()..]))
+        }
+
+        offset += line.len();
+    }
+
+    Ok((None, text))
+}
+
+/// Front matter is read by a small parser rather than a full yaml or toml one, so anything it
+/// doesn't understand is an error rather than being quietly read as something else
+fn unsupported(what: &str, line: &str) -> ServError {
+    ServError::new(500, &format!("{} aren't supported in front matter: {}", what, line.trim()))
+}
+
+/// Read a value in front matter. Only the simple values that front matter is usually made of
+/// are understood: strings, numbers, booleans and inline lists like `[a, "b"]`.
+fn parse_scalar(text: &str) -> Result<ServValue, ServError> {
+    let text = text.trim();
+
+    if text.starts_with('[') {
+        let Some(inner) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) else {
+            return Err(unsupported("multiline lists", text))
+        };
+
+        let items = split_list(inner).into_iter()
+            .filter(|item| !item.trim().is_empty())
+            .map(parse_scalar)
+            .collect::<Result<Vec<ServValue>, ServError>>()?;
+
+        return Ok(ServValue::List(items.into()))
+    }
+
+    if text.starts_with('{') {
+        return Err(unsupported("inline tables", text))
+    }
+
+    if text.starts_with("\"\"\"") || text.starts_with("'''") {
+        return Err(unsupported("multiline strings", text))
+    }
+
+    for quote in ['"', '\''] {
+        if text.starts_with(quote) {
+            if text.len() < 2 || !text.ends_with(quote) {
+                return Err(unsupported("unterminated strings", text))
+            }
+
+            return Ok(ServValue::from(text[1..text.len() - 1].to_owned()))
+        }
+    }
+
+    Ok(match text {
+        "" | "~" | "null" => ServValue::None,
+        "true"  => ServValue::Bool(true),
+        "false" => ServValue::Bool(false),
+        _ => text.parse::<i64>().map(ServValue::Int)
+            .or_else(|_| text.parse::<f64>().map(ServValue::Float))
+            .unwrap_or_else(|_| ServValue::from(text.to_owned())),
+    })
+}
+
+/// Split the items of an inline list on commas that aren't inside of quotes
+fn split_list(text: &str) -> Vec<&str> {
+    let mut output = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+
+    for (i, c) in text.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (',', None) => { output.push(&text[start..i]); start = i + 1; },
+            _ => {},
+        }
+    }
+
+    output.push(&text[start..]);
+    output
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find(" #") {
+        Some(i) if !line[..i].contains(['"', '\'']) => &line[..i],
+        _ if line.trim_start().starts_with('#') => "",
+        _ => line,
+    }
+}
+
+/// `key: value` pairs, along with lists written as `- item` lines under a key with no value
+fn parse_yaml(text: &str) -> Result<FrontMatter, ServError> {
+    let mut output = HashMap::new();
+    let mut list: Option<(String, Vec<ServValue>)> = None;
+
+    for line in text.lines().map(strip_comment) {
+        if line.trim().is_empty() { continue };
+
+        if let Some(item) = line.trim_start().strip_prefix("- ") {
+            let Some((_, ref mut items)) = list else {
+                return Err(unsupported("list items without a key", line))
+            };
+
+            let quoted = item.trim_start().starts_with(['"', '\'']);
+            if !quoted && (item.contains(": ") || item.trim_end().ends_with(':')) {
+                return Err(unsupported("lists of maps", line))
+            }
+
+            items.push(parse_scalar(item)?);
+            continue
+        }
+
+        if line.starts_with([' ', '\t']) {
+            return Err(unsupported("nested maps", line))
+        }
+
+        if let Some((key, items)) = list.take() {
+            output.insert(key, ServValue::List(items.into()));
+        }
+
+        let Some((key, value)) = line.split_once(':') else {
+            return Err(unsupported("lines without a key", line))
+        };
+
+        let key = key.trim().to_owned();
+
+        match value.trim() {
+            "" => list = Some((key, Vec::new())),
+            value if value.starts_with(['|', '>']) => return Err(unsupported("multiline strings", line)),
+            value => { output.insert(key, parse_scalar(value)?); },
+        }
+    }
+
+    if let Some((key, items)) = list {
+        output.insert(key, ServValue::List(items.into()));
+    }
+
+    Ok(output)
+}
+
+/// `key = value` pairs, with `[section]` headers putting the keys after them into a table
+fn parse_toml(text: &str) -> Result<FrontMatter, ServError> {
+    let mut output = HashMap::new();
+    let mut section: Option<(String, HashMap<String, ServValue>)> = None;
+
+    for line in text.lines().map(strip_comment) {
+        let line = line.trim();
+        if line.is_empty() { continue };
+
+        if line.starts_with("[[") {
+            return Err(unsupported("arrays of tables", line))
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            if name.contains('.') {
+                return Err(unsupported("nested tables", line))
+            }
+
+            if let Some((name, table)) = section.take() {
+                output.insert(name, ServValue::Table(table));
+            }
+
+            section = Some((name.trim().to_owned(), HashMap::new()));
+            continue
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(unsupported("lines without a key", line))
+        };
+
+        let key = key.trim();
+        if !key.starts_with('"') && key.contains('.') {
+            return Err(unsupported("dotted keys", line))
+        }
+
+        let key = key.trim_matches('"').to_owned();
+        let value = parse_scalar(value)?;
+
+        match section {
+            Some((_, ref mut table)) => table.insert(key, value),
+            None => output.insert(key, value),
+        };
+    }
+
+    if let Some((name, table)) = section {
+        output.insert(name, ServValue::Table(table));
+    }
+
+    Ok(output)
+}
+
+/// Turn the text of a heading into an id, ie. `Getting Started!` becomes `getting-started`
+fn slugify(text: &str) -> String {
+    let mut output = String::new();
+    for word in text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+        if !output.is_empty() { output.push('-') };
+        output.push_str(&word.to_lowercase());
+    }
+
+    output
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut output = String::new();
+    let mut in_tag = false;
+
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => output.push(c),
+            _ => {},
+        }
+    }
+
+    output
+}
+
+/// Give every heading in rendered html an id, and list them in order for a table of contents.
+/// An id that is already taken gets the next free number added to it, so that every id is unique,
+/// and headings without any letters or numbers in them get `section` instead.
+fn add_anchors(html: &str) -> (String, Vec<ServValue>) {
+    let mut output = String::with_capacity(html.len());
+    let mut toc = Vec::new();
+    let mut used: HashSet<String> = HashSet::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find("<h") {
+        let tag = &rest[start..];
+        let level = tag.as_bytes().get(2).copied().filter(|c| (b'1'..=b'6').contains(c));
+        let (Some(level), Some(b'>')) = (level, tag.as_bytes().get(3).copied()) else {
+            output.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            continue
+        };
+
+        let close = format!("</h{}>", level as char);
+        let Some(end) = tag.find(&close) else { break };
+
+        let inner = &tag[4..end];
+        let text = strip_tags(inner);
+        let base = match slugify(&text) {
+            slug if slug.is_empty() => "section".to_owned(),
+            slug => slug,
+        };
+
+        let mut id = base.clone();
+        let mut n = 1;
+        while !used.insert(id.clone()) {
+            n += 1;
+            id = format!("{}-{}", base, n);
+        }
+
+        output.push_str(&rest[..start]);
+        output.push_str(&format!("<h{} id=\"{}\">{}{}", level as char, id, inner, close));
+
+        let mut entry = HashMap::new();
+        entry.insert("level".to_owned(), ServValue::Int((level - b'0') as i64));
+        // the text is still escaped, so it is already safe to put into a page
+        entry.insert("title".to_owned(), ServString::from(text).mark_safe().as_value());
+        entry.insert("id".to_owned(), ServValue::from(id));
+        toc.push(ServValue::Table(entry));
+
+        rest = &tag[end + close.len()..];
+    }
+
+    output.push_str(rest);
+    (output, toc)
+}
+
+/// Parse a document into a table with its front matter as `meta`, the rendered html as `body`,
+/// and the headings in the document as `toc`
+fn parse_document(text: &str) -> Result<HashMap<String, ServValue>, ServError> {
+    let (meta, body) = split_front_matter(text)?;
+    let (html, toc) = add_anchors(&to_html(body));
+
+    let mut meta = meta.unwrap_or_default();
+    if !meta.contains_key("title") {
+        // fall back to the first top level heading
+        let title = toc.iter().find_map(|entry| match entry {
+            ServValue::Table(t) if matches!(t.get("level"), Some(ServValue::Int(1))) => t.get("title").cloned(),
+            _ => None,
+        });
+
+        if let Some(title) = title { meta.insert("title".to_owned(), title); }
+    }
+
+    let mut output = HashMap::new();
+    output.insert("meta".to_owned(), ServValue::Table(meta));
+    output.insert("body".to_owned(), ServString::from(html).mark_safe().as_value());
+    output.insert("toc".to_owned(), ServValue::List(toc.into()));
+    Ok(output)
+}
+
+/// Convert markdown text into html, leaving out any front matter
+fn markdown(input: ServValue, scope: &Stack) -> ServResult {
+    let text = input.to_string();
+    let (_, body) = split_front_matter(&text)?;
+    Ok(ServString::from(to_html(body)).mark_safe().as_value())
+}
+
+/// `markdown.safe` converts markdown that could have come from anyone, like a comment, escaping
+/// any html and leaving out links to scripts, so that it can't add markup of its own to a page
+fn markdown_safe(input: ServValue, scope: &Stack) -> ServResult {
+    Ok(ServString::from(convert(&input.to_string(), false)).mark_safe().as_value())
+}
+
+/// `markdown.document (file {post.md})` returns a table with the document's front matter as
+/// `meta`, the rendered html as `body`, and a table of contents as `toc`, with a `level`,
+/// `title` and `id` for each heading. Every heading in the body gets an id to link to.
+fn markdown_document(input: ServValue, scope: &Stack) -> ServResult {
+    Ok(ServValue::Table(parse_document(&input.to_string())?))
+}
+
+fn get_date(post: &ServValue) -> String {
+    let ServValue::Table(post) = post else { return String::new() };
+    match post.get("meta") {
+        Some(ServValue::Table(meta)) => meta.get("date").map(|d| d.to_string()).unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+/// `markdown.dir {posts/}` reads every markdown file in a directory as a document, adding the
+/// `path` of the file and a `slug` from its name, sorted by the `date` in their front matter with
+/// the newest first. Dates are compared as text, so they should be written like `2024-05-01`.
+fn markdown_dir(input: ServValue, scope: &Stack) -> ServResult {
+    let dir = input.to_string();
+    let mut posts = Vec::new();
+
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if !path.extension().is_some_and(|e| e == "md") { continue };
+
+        let text = std::fs::read_to_string(&path)?;
+        super::host::note_file_read(&path);
+
+        let mut post = parse_document(&text)?;
+        let slug = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        post.insert("slug".to_owned(), ServValue::from(slug));
+        post.insert("path".to_owned(), ServValue::from(path.to_string_lossy().into_owned()));
+        posts.push(ServValue::Table(post));
+    }
+
+    posts.sort_by_cached_key(|post| std::cmp::Reverse(get_date(post)));
+    Ok(ServValue::List(posts.into()))
+}
+
+pub fn get_module() -> ServModule {
+    let mut output = ServModule::empty();
+	output.insert("markdown",          ServFn::Core(markdown).into());
+	output.insert("markdown.safe",     ServFn::Core(markdown_safe).into());
+	output.insert("markdown.document", ServFn::Core(markdown_document).into());
+	output.insert("markdown.dir",      ServFn::Core(markdown_dir).into());
+	output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_front_matter() {
+        let (meta, body) = split_front_matter("---\ntitle: \"Hello, world\"\ndate: 2024-05-01\ntags:\n  - rust\n  - web\n---\n# Hi\n").unwrap();
+        let meta = meta.unwrap();
+        assert_eq!(meta.get("title").unwrap().to_string(), "Hello, world");
+        assert_eq!(meta.get("date").unwrap().to_string(), "2024-05-01");
+        assert!(matches!(meta.get("tags"), Some(ServValue::List(l)) if l.len() == 2));
+        assert_eq!(body, "# Hi\n");
+    }
+
+    #[test]
+    fn toml_front_matter() {
+        let (meta, _) = split_front_matter("+++\ntitle = 'Hi'\ndraft = true\ntags = [\"a\", \"b, c\"]\n+++\ntext").unwrap();
+        let meta = meta.unwrap();
+        assert!(matches!(meta.get("draft"), Some(ServValue::Bool(true))));
+        assert!(matches!(meta.get("tags"), Some(ServValue::List(l)) if l.len() == 2));
+    }
+
+    #[test]
+    fn no_front_matter() {
+        let (meta, body) = split_front_matter("--- not front matter").unwrap();
+        assert!(meta.is_none());
+        assert_eq!(body, "--- not front matter");
+    }
+
+    #[test]
+    fn unsupported_front_matter() {
+        for text in [
+            "---\nauthor:\n  name: me\n---\n",
+            "---\nsummary: |\n  some text\n---\n",
+            "---\nlinks:\n  - title: a\n---\n",
+            "+++\n[[links]]\ntitle = 'a'\n+++\n",
+            "+++\nsummary = \"\"\"\ntext\n\"\"\"\n+++\n",
+            "+++\nauthor = { name = 'me' }\n+++\n",
+        ] {
+            assert!(split_front_matter(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn heading_anchors() {
+        let (html, toc) = add_anchors("<h1>Intro</h1><p>x</p><h2>Intro</h2><h2><code>a</code> b</h2><hr>");
+        assert_eq!(html, "<h1 id=\"intro\">Intro</h1><p>x</p><h2 id=\"intro-2\">Intro</h2><h2 id=\"a-b\"><code>a</code> b</h2><hr>");
+        assert_eq!(toc.len(), 3);
+    }
+
+    #[test]
+    fn heading_anchors_are_unique() {
+        let (html, _) = add_anchors("<h2>Intro 2</h2><h2>Intro</h2><h2>Intro</h2><h2>!!</h2><h2>?</h2>");
+        assert_eq!(html, "<h2 id=\"intro-2\">Intro 2</h2><h2 id=\"intro\">Intro</h2><h2 id=\"intro-3\">Intro</h2><h2 id=\"section\">!!</h2><h2 id=\"section-2\">?</h2>");
+    }
+
+    #[test]
+    fn html_in_markdown() {
+        let text = "<div class=\"note\">hi</div>\n\n[link](javascript:alert(1))";
+        let trusted = to_html(text);
+        assert!(trusted.contains("<div class=\"note\">hi</div>"));
+
+        let untrusted = convert(text, false);
+        assert!(untrusted.contains("&lt;div class=&quot;note&quot;&gt;hi&lt;/div&gt;"), "{}", untrusted);
+        assert!(!untrusted.contains("javascript:"), "{}", untrusted);
+    }
+}
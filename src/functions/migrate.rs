@@ -0,0 +1,260 @@
+//! numbered schema migrations for sqlite databases
+
+use crate::{ServValue, ServResult, ServError, Stack};
+use crate::engine::pool::{SqlitePool, PoolOptions};
+
+use super::sql::{with_connection, begin_transaction};
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS _serv_migrations (
+    version INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    checksum TEXT NOT NULL,
+    applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);";
+
+/// A file in the migrations directory, named like `001_create_users.sql` or `002_seed.serv`
+struct Migration {
+    version: i64,
+    name: String,
+    contents: String,
+    checksum: String,
+}
+
+/// A migration that has been recorded in the `_serv_migrations` table
+struct Applied {
+    name: String,
+    checksum: String,
+}
+
+/// FNV-1a, which is plenty to notice that a migration has been edited after it was applied
+fn checksum(text: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in text.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    format!("{:016x}", hash)
+}
+
+/// Read every migration in a directory, sorted by version. Files that don't start with a
+/// version number, or that aren't .sql or .serv files, are ignored.
+fn read_migrations(dir: &Path) -> Result<Vec<Migration>, ServError> {
+    let mut output: Vec<Migration> = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()).map(|n| n.to_owned()) else { continue };
+        if !name.ends_with(".sql") && !name.ends_with(".serv") { continue };
+
+        let digits: String = name.chars().take_while(|c| c.is_ascii_digit()).collect();
+        let Ok(version) = digits.parse::<i64>() else { continue };
+
+        if let Some(other) = output.iter().find(|m| m.version == version) {
+            return Err(ServError::new(500, &format!("migrations {} and {} have the same version", other.name, name)))
+        }
+
+        let contents = std::fs::read_to_string(&path)?;
+        output.push(Migration { version, checksum: checksum(&contents), name, contents });
+    }
+
+    output.sort_by_key(|m| m.version);
+    Ok(output)
+}
+
+fn read_applied(connection: &sqlite::Connection) -> Result<HashMap<i64, Applied>, ServError> {
+    let mut statement = connection.prepare("SELECT version, name, checksum FROM _serv_migrations;")?;
+    let mut output = HashMap::new();
+
+    while let sqlite::State::Row = statement.next()? {
+        let version = statement.read::<i64, _>(0)?;
+        let name = statement.read::<String, _>(1)?;
+        let checksum = statement.read::<String, _>(2)?;
+        output.insert(version, Applied { name, checksum });
+    }
+
+    Ok(output)
+}
+
+fn record(connection: &sqlite::Connection, migration: &Migration) -> Result<(), ServError> {
+    let mut statement = connection.prepare("INSERT INTO _serv_migrations (version, name, checksum) VALUES (?, ?, ?);")?;
+    statement.bind((1, migration.version))?;
+    statement.bind((2, migration.name.as_str()))?;
+    statement.bind((3, migration.checksum.as_str()))?;
+    while let sqlite::State::Row = statement.next()? {}
+    Ok(())
+}
+
+fn run(migration: &Migration, scope: &mut Stack) -> Result<(), ServError> {
+    if migration.name.ends_with(".serv") {
+        let module = crate::parser::parse_root_from_text(&migration.contents, scope)?;
+        module.call(None, scope)?;
+    } else {
+        with_connection(scope, |connection| Ok(connection.execute(&migration.contents)?))?;
+    }
+
+    with_connection(scope, |connection| record(connection, migration))
+}
+
+/// Run a migration and record it in a single transaction, so that a migration that fails
+/// part way through leaves nothing behind
+fn apply(migration: &Migration, scope: &Stack) -> Result<(), ServError> {
+    let transaction = begin_transaction(scope)?;
+    let mut child = scope.make_child();
+    child.transaction = Some(transaction.clone());
+
+    transaction.finish(run(migration, &mut child))
+        .map_err(|e| ServError::new(500, &format!("migration {} failed: {}", migration.name, e)))
+}
+
+/// `sqlite.migrate {migrations/}` applies every migration in a directory that hasn't been
+/// applied yet, in order, and returns how many it applied. It fails if a migration that was
+/// already applied has since been changed.
+pub fn sqlite_migrate(input: ServValue, scope: &Stack) -> ServResult {
+    let migrations = read_migrations(Path::new(&input.to_string()))?;
+
+    with_connection(scope, |connection| Ok(connection.execute(CREATE_TABLE)?))?;
+    let applied = with_connection(scope, read_applied)?;
+
+    let mut count = 0;
+    for migration in migrations.iter() {
+        match applied.get(&migration.version) {
+            Some(a) if a.checksum == migration.checksum => continue,
+            Some(a) => return Err(ServError::new(500, &format!("migration {} has changed since it was applied", a.name))),
+            None => {},
+        }
+
+        apply(migration, scope)?;
+        println!("applied migration {}", migration.name);
+        count += 1;
+    }
+
+    Ok(ServValue::Int(count))
+}
+
+/// Print which migrations in a directory have been applied to a database, for `serv migrate status`
+pub fn print_migration_status(database: &str, dir: &str) -> Result<(), ServError> {
+    let migrations = read_migrations(Path::new(dir))?;
+
+    let options = PoolOptions { size: 1, readonly: true, ..PoolOptions::default() };
+    let pool = Arc::new(SqlitePool::open(database, options)?);
+    let connection = pool.get()?;
+
+    // a database that has never been migrated doesn't have the table yet
+    let applied = read_applied(&connection).unwrap_or_default();
+
+    for migration in migrations.iter() {
+        let status = match applied.get(&migration.version) {
+            Some(a) if a.checksum == migration.checksum => "applied",
+            Some(_) => "changed",
+            None    => "pending",
+        };
+
+        println!("{:<8} {}", status, migration.name);
+    }
+
+    let mut missing: Vec<_> = applied.iter()
+        .filter(|(version, _)| !migrations.iter().any(|m| m.version == **version))
+        .collect();
+
+    missing.sort_by_key(|(version, _)| **version);
+    for (_, a) in missing {
+        println!("{:<8} {}", "missing", a.name);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of migrations and a database to apply them to, removed once the test is done
+    struct TempMigrations(std::path::PathBuf);
+
+    impl TempMigrations {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("serv-migrations-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(dir.join("migrations")).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, name: &str, contents: &str) {
+            std::fs::write(self.0.join("migrations").join(name), contents).unwrap();
+        }
+
+        /// Connect to the database and migrate it, returning how many migrations were applied
+        fn migrate(&self) -> ServResult {
+            let source = format!(
+                "sqlite.connect {{{}}}\nsqlite.migrate {{{}}}",
+                self.0.join("test.db").display(),
+                self.0.join("migrations").display(),
+            );
+
+            let mut scope = Stack::empty();
+            scope.insert_module(crate::functions::standard_library().values);
+            let module = crate::parser::parse_root_from_text(&source, &mut scope)?;
+
+            let mut output = ServValue::None;
+            for expr in module.statements {
+                output = crate::engine::eval(expr, &mut scope)?;
+            }
+
+            Ok(output)
+        }
+    }
+
+    impl Drop for TempMigrations {
+        fn drop(&mut self) {
+            _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn applies_pending_migrations_in_order() {
+        let dir = TempMigrations::new("order");
+        dir.write("002_seed.sql", "INSERT INTO users (name) VALUES ('alice');");
+        dir.write("001_create_users.sql", "CREATE TABLE users (name TEXT);");
+        dir.write("readme.md", "not a migration");
+
+        assert!(matches!(dir.migrate().unwrap(), ServValue::Int(2)));
+        assert!(matches!(dir.migrate().unwrap(), ServValue::Int(0)));
+
+        dir.write("003_more.sql", "INSERT INTO users (name) VALUES ('bob');");
+        assert!(matches!(dir.migrate().unwrap(), ServValue::Int(1)));
+    }
+
+    #[test]
+    fn refuses_changed_migrations() {
+        let dir = TempMigrations::new("changed");
+        dir.write("001_create_users.sql", "CREATE TABLE users (name TEXT);");
+        dir.migrate().unwrap();
+
+        dir.write("001_create_users.sql", "CREATE TABLE users (name TEXT, email TEXT);");
+        let error = dir.migrate().unwrap_err();
+        assert!(error.to_string().contains("001_create_users.sql has changed"), "{}", error);
+    }
+
+    #[test]
+    fn failed_migrations_leave_nothing_behind() {
+        let dir = TempMigrations::new("failed");
+        dir.write("001_create_users.sql", "CREATE TABLE users (name TEXT); not sql;");
+        assert!(dir.migrate().is_err());
+
+        // the table wasn't created, and the migration wasn't recorded, so a fixed one applies
+        dir.write("001_create_users.sql", "CREATE TABLE users (name TEXT);");
+        assert!(matches!(dir.migrate().unwrap(), ServValue::Int(1)));
+    }
+
+    #[test]
+    fn duplicate_versions_are_an_error() {
+        let dir = TempMigrations::new("duplicate");
+        dir.write("001_a.sql", "");
+        dir.write("1_b.sql", "");
+        assert!(dir.migrate().is_err());
+    }
+}
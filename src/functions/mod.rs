@@ -19,23 +19,47 @@ use std::io::Read;
 mod host;
 mod list;
 mod sql;
+mod migrate;
+mod search;
 mod request;
 mod math;
 mod core;
 mod string;
+mod layout;
+mod markdown;
+mod highlight;
+mod events;
+mod state;
+mod ratelimit;
+mod auth;
 
 pub mod json;
 
+pub use host::{record_files_read, take_files_read, stop_recording_files};
+pub use events::{subscribe, send, Subscription};
+pub use sql::{stream_query, USING_DATABASE};
+pub use migrate::print_migration_status;
+pub use search::sync_search_index;
+pub use ratelimit::{Rate, take, client_key, MATCHED_ROUTE};
+
 pub fn standard_library() -> ServModule {
     let mut output = ServModule::empty();
     output.values.extend(core::get_module().values);
     output.values.extend(string::get_module().values);
+    output.values.extend(layout::get_module().values);
+    output.values.extend(markdown::get_module().values);
+    output.values.extend(highlight::get_module().values);
     output.values.extend(math::get_module().values);
     output.values.extend(list::get_module().values);
     output.values.extend(request::get_module().values);
     output.values.extend(json::get_module().values);
     output.values.extend(host::get_module().values);
     output.values.extend(sql::get_module().values);
+    output.values.extend(events::get_module().values);
+    output.values.extend(state::get_module().values);
+    output.values.extend(search::get_module().values);
+    output.values.extend(ratelimit::get_module().values);
+    output.values.extend(auth::get_module().values);
 
     output
 
@@ -0,0 +1,214 @@
+//! token bucket rate limits, which are held in memory and keyed by client
+
+use crate::{ServValue, ServResult, ServError, Stack, ServFn, ServModule, Label};
+use crate::value::ServList;
+
+use hyper::http::request::Parts;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, LazyLock};
+use std::time::{Duration, Instant};
+
+/// The most buckets that are kept. Past this, the ones used least recently are forgotten.
+const MAX_BUCKETS: usize = 10_000;
+
+/// How often buckets that have filled back up are forgotten, since they are the same as new ones
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many requests are allowed over a period, ie. `10/min`
+#[derive(Clone, Copy)]
+pub struct Rate {
+    limit: u32,
+    per: Duration,
+}
+
+impl Rate {
+    pub fn parse(text: &str) -> Result<Self, ServError> {
+        let invalid = || ServError::new(500, &format!("invalid rate limit {}, expected something like 10/min", text));
+        let (limit, period) = text.trim().split_once('/').ok_or_else(invalid)?;
+
+        let per = match period.trim() {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour"           => Duration::from_secs(60 * 60),
+            "d" | "day"            => Duration::from_secs(60 * 60 * 24),
+            _ => return Err(invalid()),
+        };
+
+        let limit = limit.trim().parse().ok().filter(|l| *l > 0).ok_or_else(invalid)?;
+        Ok(Self { limit, per })
+    }
+
+    fn refill_per_second(&self) -> f64 {
+        self.limit as f64 / self.per.as_secs_f64()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.refill_per_second()).min(rate.limit as f64);
+        self.updated = now;
+    }
+}
+
+struct Buckets {
+    map: HashMap<String, (Rate, Bucket)>,
+    swept: Instant,
+}
+
+impl Buckets {
+    /// Forget the buckets that have filled back up
+    fn sweep(&mut self, now: Instant) {
+        self.map.retain(|_, (rate, bucket)| {
+            bucket.refill(*rate, now);
+            bucket.tokens < rate.limit as f64
+        });
+
+        self.swept = now;
+    }
+
+    /// Forget the tenth of the buckets that were used least recently, so that a flood of new
+    /// clients can't grow the map without limit
+    fn evict_oldest(&mut self) {
+        let mut ages: Vec<(Instant, String)> = self.map.iter().map(|(k, (_, b))| (b.updated, k.clone())).collect();
+        let count = (MAX_BUCKETS / 10).clamp(1, ages.len());
+        ages.select_nth_unstable(count - 1);
+
+        for (_, key) in &ages[..count] {
+            self.map.remove(key);
+        }
+    }
+}
+
+static BUCKETS: LazyLock<Mutex<Buckets>> = LazyLock::new(|| Mutex::new(Buckets { map: HashMap::new(), swept: Instant::now() }));
+
+/// Take a token from a bucket, or fail with how long to wait until one is available
+pub fn take(key: &str, rate: Rate) -> Result<(), ServError> {
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().unwrap_or_else(|e| e.into_inner());
+
+    if now.duration_since(buckets.swept) >= SWEEP_INTERVAL {
+        buckets.sweep(now);
+    }
+
+    if buckets.map.len() >= MAX_BUCKETS && !buckets.map.contains_key(key) {
+        buckets.evict_oldest();
+    }
+
+    let (_, bucket) = buckets.map.entry(key.to_owned())
+        .or_insert_with(|| (rate, Bucket { tokens: rate.limit as f64, updated: now }));
+
+    bucket.refill(rate, now);
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        return Ok(())
+    }
+
+    let wait = (1.0 - bucket.tokens) / rate.refill_per_second();
+    Err(ServError::RateLimited(wait.ceil() as u64))
+}
+
+/// The remote address of a request. Behind a proxy on a unix socket there isn't one,
+/// so the address the proxy forwarded is used instead.
+fn remote_address(parts: &Parts) -> String {
+    if let Some(addr) = parts.extensions.get::<SocketAddr>() {
+        return addr.ip().to_string()
+    }
+
+    parts.headers.get("X-Forwarded-For")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_owned())
+        .unwrap_or_default()
+}
+
+fn cookie(parts: &Parts, name: &str) -> Option<String> {
+    let header = parts.headers.get("Cookie")?.to_str().ok()?;
+    header.split(';')
+        .filter_map(|c| c.split_once('='))
+        .find(|(k, _)| k.trim() == name)
+        .map(|(_, v)| v.trim().to_owned())
+}
+
+/// Which client a request came from, according to a key like `ip`, `header:X-Api-Key` or `cookie:session`.
+/// Requests without the header or cookie are keyed by their remote address.
+pub fn client_key(key: &str, parts: &Parts) -> Result<String, ServError> {
+    let found = match key.split_once(':') {
+        None if key == "ip" => None,
+        Some(("header", name)) => parts.headers.get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_owned()),
+        Some(("cookie", name)) => cookie(parts, name),
+        _ => return Err(ServError::new(500, &format!("invalid rate limit key {}, expected ip, header:Name or cookie:Name", key))),
+    };
+
+    Ok(found.map(|v| format!("{}:{}", key, v)).unwrap_or_else(|| format!("ip:{}", remote_address(parts))))
+}
+
+/// Read a rate from the front of an expression. `10/min` is written without brackets, in
+/// which case it is read as the int `10` followed by the route `/min`.
+fn pop_rate(input: &mut ServList, scope: &mut Stack) -> Result<Rate, ServError> {
+    let first = input.pop()?;
+    let ServValue::Int(limit) = first else {
+        return Rate::parse(&first.call(None, scope)?.to_string())
+    };
+
+    let period = match input.get(0) {
+        Ok(ServValue::Ref(addr)) if addr.len() == 1 => match addr.iter().next() {
+            Some(Label::Route(period)) => Some(period.clone()),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    let Some(period) = period else {
+        return Err(ServError::new(500, "ratelimit expects a rate, ie. ratelimit 10/min ..."))
+    };
+
+    input.pop()?;
+    Rate::parse(&format!("{}{}", limit, period))
+}
+
+/// The word that the pattern of the route being evaluated is bound to, like `/users/{id}`. It
+/// can't be written in a serv script, so it can't collide with anything a script defines.
+pub const MATCHED_ROUTE: &str = ":route";
+
+/// Requests are counted per route rather than per path, so that a client can't get around the
+/// limit on `/users/{id}` by asking for a different id each time
+fn limit(key: &str, rate: Rate, input: &mut ServList, scope: &mut Stack) -> ServResult {
+    let Some(parts) = scope.get_request() else { return input.eval(scope) };
+    let client = client_key(key, parts)?;
+    let route = match scope.get(Label::Name(MATCHED_ROUTE.to_owned())) {
+        Ok(pattern) => pattern.to_string(),
+        Err(_) => parts.uri.path().to_owned(),
+    };
+
+    take(&format!("{}|{}", route, client), rate)?;
+    input.eval(scope)
+}
+
+/// `ratelimit 10/min ...` answers with a 429 once a client has made more than 10 requests
+/// to this route in a minute, and otherwise evaluates the rest of the expression
+fn ratelimit(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let rate = pop_rate(&mut input, scope)?;
+    limit("ip", rate, &mut input, scope)
+}
+
+/// `ratelimit.by {header:X-Api-Key} 10/min ...` is `ratelimit`, keyed by something other than
+/// the client's remote address
+fn ratelimit_by(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let key = input.pop()?.call(None, scope)?.to_string();
+    let rate = pop_rate(&mut input, scope)?;
+    limit(&key, rate, &mut input, scope)
+}
+
+pub fn get_module() -> ServModule {
+    let mut output = ServModule::empty();
+	output.insert("ratelimit",    ServFn::Meta(ratelimit).into());
+	output.insert("ratelimit.by", ServFn::Meta(ratelimit_by).into());
+	output
+}
@@ -12,6 +12,7 @@ use crate::datatypes::servlist::ServList;
 use crate::{Label, ServFn};
 
 use crate::ServModule;
+use crate::ServType;
 
 use std::collections::HashMap;
 
@@ -64,7 +65,9 @@ fn get_cookies(input: ServValue, scope: &Stack) -> ServResult {
     let Some(req) = scope.get_request() else { return Ok(ServValue::None) };
 	let cookie = req.headers.get("Cookie").ok_or(ServError::new(500, "expected a cookie"))?;
 
-	Ok(parse_cookie(cookie.to_str().unwrap()))
+	let text = cookie.to_str().map_err(|_| ServError::new(400, "invalid cookie header"))?;
+
+	Ok(parse_cookie(text))
 }
 
 fn set_cookie(mut input: ServList, scope: &mut Stack) -> ServResult {
@@ -75,14 +78,24 @@ fn set_cookie(mut input: ServList, scope: &mut Stack) -> ServResult {
     input.eval(scope)
 }
 
-fn with_headers(mut input: ServList, scope: &mut Stack) -> ServResult {
-    let mut arg = input.pop()?;
-    arg = crate::engine::resolve(arg, None, scope)?;
-    println!("{:?}", arg);
-	// arg = arg.call(None, scope)?;
-
-    scope.insert("res.headers", arg);
-    // println!("{:#?}", scope.get("res.headers"));
+/// `with.headers ("X-Frame-Options" = {DENY}) ...` adds headers to the response, keeping any
+/// that were already added. `headers` is the same function.
+fn headers(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let added = match crate::engine::resolve(input.pop()?, None, scope)? {
+        ServValue::Table(t) => t,
+        otherwise => return Err(ServError::expected_type(ServType::Table, otherwise)),
+    };
+
+    let mut output: HashMap<String, ServValue> = match crate::engine::deref(&"res.headers".into(), scope) {
+        Ok(ServValue::Table(t)) => t,
+        Ok(ServValue::Module(m)) => m.values.into_iter()
+            .map(|(k, v)| Ok((k.to_string(), v.call(None, scope)?)))
+            .collect::<Result<_, ServError>>()?,
+        _ => HashMap::new(),
+    };
+
+    output.extend(added);
+    scope.insert("res.headers", ServValue::Table(output))?;
     input.eval(scope)
 }
 
@@ -93,7 +106,8 @@ pub fn get_module() -> ServModule {
 	output.insert("req.query",    ServFn::Core(query_all).into());
 	output.insert("cookies",      ServFn::Core(get_cookies).into());
 	output.insert("cookie.set",   ServFn::Meta(set_cookie).into());
-	// output.insert("with.headers", ServFn::Meta(with_headers).into());
+	output.insert("with.headers", ServFn::Meta(headers).into());
+	output.insert("headers",      ServFn::Meta(headers).into());
 
 	output
 }
@@ -0,0 +1,348 @@
+//! full text search over markdown files or query results, using sqlite's fts5 extension
+
+use crate::{ServValue, ServResult, ServError, Stack, ServFn, ServModule};
+use crate::engine::pool::SqlitePool;
+use crate::template::escape_html;
+use crate::ServString;
+
+use super::sql::{get_pool, with_connection};
+use super::markdown::split_front_matter;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, LazyLock};
+use std::time::SystemTime;
+
+const CREATE_TABLE: &str = "CREATE VIRTUAL TABLE IF NOT EXISTS _serv_search USING fts5(source UNINDEXED, path UNINDEXED, title, body);";
+
+const QUERY: &str = "SELECT path, title, snippet(_serv_search, 3, '<mark>', '</mark>', '…', 16) AS snippet, bm25(_serv_search) AS rank
+FROM _serv_search WHERE _serv_search MATCH ? ORDER BY rank LIMIT ?;";
+
+const MAX_RESULTS: i64 = 20;
+
+/// The source of rows indexed with `search.index query {...}`, which can't be a directory
+const QUERY_SOURCE: &str = ":query";
+
+/// A directory of markdown files that has been indexed, along with the time each file was
+/// last modified, so that only files that have changed are indexed again
+struct Source {
+    dir: PathBuf,
+    pool: Arc<SqlitePool>,
+    files: HashMap<PathBuf, SystemTime>,
+}
+
+/// Indexed directories by path, so that indexing a directory again after a reload replaces it
+static SOURCES: LazyLock<Mutex<HashMap<PathBuf, Source>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn markdown_files(dir: &Path, output: &mut Vec<PathBuf>) -> Result<(), ServError> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            markdown_files(&path, output)?;
+        } else if path.extension().is_some_and(|e| e == "md") {
+            output.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Render markdown and strip the tags, leaving text that is still html escaped, so that
+/// snippets of it can be put straight into a page
+fn to_plain_text(markdown: &str) -> String {
+    let html = super::markdown::to_html(markdown);
+    let mut output = String::new();
+    let mut in_tag = false;
+
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => { in_tag = false; output.push(' ') },
+            c if !in_tag => output.push(c),
+            _ => {},
+        }
+    }
+
+    output.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The title in a document's front matter, or its first top level heading, or its file name
+fn get_title(meta: Option<HashMap<String, ServValue>>, text: &str, path: &Path) -> String {
+    if let Some(title) = meta.and_then(|m| m.get("title").map(|t| t.to_string())) {
+        return title
+    }
+
+    text.lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .map(|title| title.trim().to_owned())
+        .unwrap_or_else(|| path.file_stem().unwrap_or_default().to_string_lossy().into_owned())
+}
+
+/// Index a document, replacing the one from the same source with the same path if there is one
+fn insert(connection: &sqlite::Connection, source: &str, path: &str, title: &str, body: &str) -> Result<(), ServError> {
+    remove(connection, source, path)?;
+
+    let mut statement = connection.prepare("INSERT INTO _serv_search (source, path, title, body) VALUES (?, ?, ?, ?);")?;
+    statement.bind(&[source, path, title, body][..])?;
+    while let sqlite::State::Row = statement.next()? {}
+    Ok(())
+}
+
+fn remove(connection: &sqlite::Connection, source: &str, path: &str) -> Result<(), ServError> {
+    let mut statement = connection.prepare("DELETE FROM _serv_search WHERE source = ? AND path = ?;")?;
+    statement.bind(&[source, path][..])?;
+    while let sqlite::State::Row = statement.next()? {}
+    Ok(())
+}
+
+fn remove_source(connection: &sqlite::Connection, source: &str) -> Result<(), ServError> {
+    let mut statement = connection.prepare("DELETE FROM _serv_search WHERE source = ?;")?;
+    statement.bind((1, source))?;
+    while let sqlite::State::Row = statement.next()? {}
+    Ok(())
+}
+
+/// Bring the index up to date with the files in a source directory. Files are indexed by their
+/// path including the directory, ie. `docs/intro.md`, so that results say where they came from.
+fn sync(source: &mut Source, connection: &sqlite::Connection) -> Result<(), ServError> {
+    let name = source.dir.to_string_lossy().into_owned();
+    let mut files = Vec::new();
+    markdown_files(&source.dir, &mut files)?;
+
+    let mut seen = HashMap::new();
+    for path in files {
+        let modified = std::fs::metadata(&path)?.modified()?;
+        if source.files.get(&path) != Some(&modified) {
+            let text = std::fs::read_to_string(&path)?;
+            let (meta, body) = split_front_matter(&text)?;
+            insert(connection, &name, &path.to_string_lossy(), &get_title(meta, body, &path), &to_plain_text(body))?;
+        }
+
+        seen.insert(path, modified);
+    }
+
+    for path in source.files.keys().filter(|p| !seen.contains_key(*p)) {
+        remove(connection, &name, &path.to_string_lossy())?;
+    }
+
+    source.files = seen;
+    Ok(())
+}
+
+fn get_field(row: &HashMap<String, ServValue>, key: &str) -> String {
+    row.get(key).map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// `search.index {docs/}` indexes every markdown file in a directory, and with `--watch`, keeps
+/// the index up to date as they change. `search.index query {...}` indexes rows with `path`, `title` and `body` columns.
+fn search_index(input: ServValue, scope: &Stack) -> ServResult {
+    let pool = get_pool(scope)?;
+    with_connection(scope, |connection| Ok(connection.execute(CREATE_TABLE)?))?;
+
+    if let ServValue::List(rows) = input {
+        with_connection(scope, |connection| {
+            for row in rows {
+                let ServValue::Table(row) = row else {
+                    return Err(ServError::new(500, "search.index expects rows with path, title and body columns"))
+                };
+
+                let body = escape_html(&get_field(&row, "body"));
+                insert(connection, QUERY_SOURCE, &get_field(&row, "path"), &get_field(&row, "title"), &body)?;
+            }
+
+            Ok(())
+        })?;
+
+        return Ok(ServValue::None)
+    }
+
+    let dir = PathBuf::from(input.to_string());
+    let mut source = Source { dir, pool, files: HashMap::new() };
+
+    with_connection(scope, |connection| {
+        // forget files that were deleted while the server wasn't running
+        remove_source(connection, &source.dir.to_string_lossy())?;
+        sync(&mut source, connection)
+    })?;
+
+    SOURCES.lock().unwrap_or_else(|e| e.into_inner()).insert(source.dir.clone(), source);
+    Ok(ServValue::None)
+}
+
+/// Bring the index of every directory given to `search.index` up to date with its files.
+/// This is called by the file watcher, so that searching never has to wait for it.
+pub fn sync_search_index() -> Result<(), ServError> {
+    let mut sources = SOURCES.lock().unwrap_or_else(|e| e.into_inner());
+    for source in sources.values_mut() {
+        let connection = source.pool.get()?;
+        sync(source, &connection)?;
+    }
+
+    Ok(())
+}
+
+/// Quote each word of a search so that punctuation can't be read as fts5 query syntax,
+/// and let the last word match as a prefix so that results show up while typing
+fn to_match_expression(terms: &str) -> Option<String> {
+    let words: Vec<String> = terms
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| format!("\"{}\"", w))
+        .collect();
+
+    if words.is_empty() { return None };
+    Some(words.join(" ") + "*")
+}
+
+/// `search.query {terms}` returns the best matches for a search, each with a `path`, `title`,
+/// `rank`, and a `snippet` of the text around the match with the matching words in `<mark>` tags
+fn search_query(input: ServValue, scope: &Stack) -> ServResult {
+    let Some(expression) = to_match_expression(&input.to_string()) else {
+        return Ok(ServValue::List(Vec::<ServValue>::new().into()))
+    };
+
+    with_connection(scope, |connection| {
+        let mut statement = connection.prepare(QUERY)?;
+        statement.bind((1, expression.as_str()))?;
+        statement.bind((2, MAX_RESULTS))?;
+
+        let mut output = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            let mut result = HashMap::new();
+            result.insert("path".to_owned(), statement.read::<String, _>("path")?.into());
+            result.insert("title".to_owned(), statement.read::<String, _>("title")?.into());
+            // the indexed text is already escaped, so the snippet can go straight into a page
+            let snippet = ServString::from(statement.read::<String, _>("snippet")?).mark_safe();
+            result.insert("snippet".to_owned(), snippet.as_value());
+            result.insert("rank".to_owned(), ServValue::Float(statement.read::<f64, _>("rank")?));
+            output.push(ServValue::Table(result));
+        }
+
+        Ok(ServValue::List(output.into()))
+    })
+}
+
+pub fn get_module() -> ServModule {
+    let mut output = ServModule::empty();
+	output.insert("search.index", ServFn::Core(search_index).into());
+	output.insert("search.query", ServFn::Core(search_query).into());
+	output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Directories of markdown files, removed once the test is done
+    struct TempDocs(PathBuf);
+
+    impl TempDocs {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("serv-search-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, path: &str, contents: &str) {
+            let path = self.0.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+    }
+
+    impl Drop for TempDocs {
+        fn drop(&mut self) {
+            _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Evaluate the statements of some serv source, keeping the scope to run searches in
+    fn load(source: &str) -> Stack<'static> {
+        let mut scope = Stack::empty();
+        scope.insert_module(crate::functions::standard_library().values);
+        let module = crate::parser::parse_root_from_text(source, &mut scope).unwrap();
+        scope.insert_module(module.values.clone());
+
+        for expr in module.statements {
+            crate::engine::eval(expr, &mut scope).unwrap();
+        }
+
+        scope
+    }
+
+    /// The paths of the results of a search, sorted so that ties in rank don't matter
+    fn search(scope: &Stack, terms: &str) -> Vec<String> {
+        let ServValue::List(results) = search_query(ServValue::from(terms.to_owned()), scope).unwrap() else { panic!("expected a list of results") };
+        let mut paths: Vec<String> = results.map(|result| match result {
+            ServValue::Table(result) => get_field(&result, "path"),
+            otherwise => panic!("expected a result, got {}", otherwise),
+        }).collect();
+
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn files_in_different_directories_are_kept_apart() {
+        let docs = TempDocs::new("sources");
+        docs.write("guide/intro.md", "# Guide\n\nwelcome to the guide");
+        docs.write("blog/intro.md", "# Blog\n\nwelcome to the blog");
+
+        let guide = docs.0.join("guide");
+        let blog = docs.0.join("blog");
+        let scope = load(&format!("sqlite.connect {{:memory:}}\nsearch.index {{{}}}\nsearch.index {{{}}}", guide.display(), blog.display()));
+
+        assert_eq!(search(&scope, "welcome"), [
+            blog.join("intro.md").to_string_lossy(),
+            guide.join("intro.md").to_string_lossy(),
+        ]);
+    }
+
+    #[test]
+    fn syncing_picks_up_changed_and_deleted_files() {
+        let docs = TempDocs::new("sync");
+        docs.write("one.md", "the first page");
+        docs.write("two.md", "the second page");
+
+        let scope = load(&format!("sqlite.connect {{:memory:}}\nsearch.index {{{}}}", docs.0.display()));
+        assert_eq!(search(&scope, "page").len(), 2);
+
+        // modification times can be too coarse to notice a change made straight away
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        docs.write("one.md", "the first document");
+        std::fs::remove_file(docs.0.join("two.md")).unwrap();
+
+        // other tests' sources are in the same list, so only this one is synced
+        let mut sources = SOURCES.lock().unwrap();
+        let source = sources.get_mut(&docs.0).unwrap();
+        let connection = source.pool.get().unwrap();
+        sync(source, &connection).unwrap();
+        drop(connection);
+        drop(sources);
+
+        assert!(search(&scope, "page").is_empty());
+        assert_eq!(search(&scope, "document"), [docs.0.join("one.md").to_string_lossy()]);
+    }
+
+    #[test]
+    fn query_rows_have_their_own_source() {
+        let scope = load("
+            sqlite.connect {:memory:}
+            search.index sqlite.query {select 'intro.md' as path, 'Intro' as title, '<b>bold</b> claims' as body;}
+        ");
+
+        assert_eq!(search(&scope, "claims"), ["intro.md"]);
+        let source = with_connection(&scope, |connection| {
+            let mut statement = connection.prepare("SELECT source FROM _serv_search;")?;
+            statement.next()?;
+            Ok(statement.read::<String, _>(0)?)
+        }).unwrap();
+        assert_eq!(source, QUERY_SOURCE);
+    }
+
+    #[test]
+    fn match_expressions_quote_each_word() {
+        assert_eq!(to_match_expression("rust's \"borrow\" check").unwrap(), "\"rust\" \"s\" \"borrow\" \"check\"*");
+        assert!(to_match_expression(" -- ").is_none());
+    }
+}
@@ -5,6 +5,9 @@ use crate::{Label, ServFn};
 use crate::ServError;
 use crate::ServModule;
 use crate::ServList;
+use crate::ServType;
+use crate::servstring::ServString;
+use crate::value::{Serializer, DefaultSerializer};
 
 use crate::template::{Template, TemplateElement, Renderer};
 use crate::template;
@@ -15,26 +18,58 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use crate::engine::dictionary::DatabaseConnection;
+use crate::engine::pool::{SqlitePool, PoolOptions, Transaction};
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
 
-struct SqliteRenderer(Vec<ServValue>);
+/// Renders a query template with a placeholder for each expression, collecting the values to
+/// bind to them. Lists are expanded into one placeholder per item, for `IN (...)` clauses. The
+/// placeholders are named, like `:_serv_1`, since plain `?`s would be numbered from 1 and
+/// collide with named parameters like `:id` that sqlite also numbers from 1.
+struct SqliteRenderer<'a> {
+    params: Vec<ServValue>,
+    scope: &'a Stack<'a>,
+}
 
 type Buffer<'a> = &'a mut (dyn std::fmt::Write + 'a);
 
-impl template::Renderer for SqliteRenderer {
+impl SqliteRenderer<'_> {
+    /// Collect a value to bind, returning the placeholder to write in its place
+    fn placeholder(&mut self, value: ServValue) -> String {
+        self.params.push(value);
+        placeholder(self.params.len())
+    }
+}
+
+/// The name of the nth parameter of a query template, counting from 1
+fn placeholder(n: usize) -> String {
+    format!(":_serv_{}", n)
+}
+
+impl template::Renderer for SqliteRenderer<'_> {
     fn render<'buf>(&mut self, input: &template::Template, dest: Buffer<'buf>) -> Result<(), ServError> {
         for element in &input.elements {
             match element {
                 TemplateElement::Text(t) => dest.write_str(t),
-                TemplateElement::Expression(e) => {
-                    self.0.push(e.clone());
-                    dest.write_str("?")
+                TemplateElement::Expression(e) => match e.call(None, self.scope)? {
+                    ServValue::List(list) => {
+                        let items: Vec<ServValue> = list.collect();
+
+                        // `in ()` is a syntax error, but `in (null)` matches nothing
+                        let placeholders = items.into_iter().map(|item| self.placeholder(item)).collect::<Vec<_>>().join(", ");
+                        dest.write_str(if placeholders.is_empty() { "NULL" } else { &placeholders })
+                    },
+                    value => {
+                        let placeholder = self.placeholder(value);
+                        dest.write_str(&placeholder)
+                    },
                 },
                 TemplateElement::Template(inner) => {
-					dest.write_str(&inner.open);
-					self.render(inner, dest);
+					dest.write_str(&inner.open)?;
+					self.render(inner, dest)?;
 					dest.write_str(&inner.close)
                 },
-            };
+            }?;
         }
 
         Ok(())
@@ -53,88 +88,399 @@ impl template::Renderer for SqliteRenderer {
 //     }
 // }
 
+/// The word that `using db.name` binds to the name of the selected database. It can't be
+/// written in a serv script, so it can't collide with anything a script defines.
+const SELECTED_DATABASE: &str = ":database";
+
+/// The word that `func@name` is parsed into, which can't be written in a serv script either
+pub const USING_DATABASE: &str = ":using_database";
+
+/// `func@name` runs func with the database named name selected, like `using db.name func`,
+/// except that the selection doesn't outlast the expression
+fn using_database(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let selector = input.pop()?.call(None, scope)?.expect_module()?;
+    let mut child = scope.make_child();
+    child.insert_module(selector.values);
+    input.eval(&mut child)
+}
+
+/// The database selected with `using db.name`, or the default database otherwise
+pub(super) fn get_pool(scope: &Stack) -> Result<Arc<SqlitePool>, ServError> {
+    let connection = match scope.get(Label::Name(SELECTED_DATABASE.to_owned())) {
+        Ok(name) => {
+            let name = name.to_string();
+            scope.get_named_connection(&name).ok_or_else(|| ServError::new(500, &format!("no database named {}", name)))?
+        },
+        Err(_) => scope.get_database_connection().ok_or("not connected to a database")?,
+    };
+
+    let DatabaseConnection::Sqlite(pool) = connection;
+    Ok(pool.clone())
+}
+
+/// Run f with the connection of the transaction in progress on the selected database,
+/// or with a connection from its pool if there isn't one
+pub(super) fn with_connection<T>(scope: &Stack, f: impl FnOnce(&sqlite::Connection) -> Result<T, ServError>) -> Result<T, ServError> {
+    let pool = get_pool(scope)?;
+    if let Some(transaction) = scope.get_transaction().filter(|t| Arc::ptr_eq(&t.pool, &pool)) {
+        return f(transaction.connection())
+    }
+
+    let connection = pool.get()?;
+    f(&connection)
+}
+
 fn sqlite_exec(input: ServValue, scope: &Stack) -> ServResult {
-    let DatabaseConnection::Sqlite(connection) = scope.get_database_connection().expect("not connected to a database");
-    connection.execute(input.to_string()).unwrap();
-    Ok(ServValue::None)
+    with_connection(scope, |connection| {
+        connection.execute(input.to_string())?;
+        Ok(ServValue::None)
+    })
 }
 
-fn sqlite_bind_param(statement: &mut sqlite::Statement, i: usize, param: ServValue, scope: &Stack) -> Result<(), ServError> {
+/// Bind a value to a parameter, which is either a position starting from 1 or a name like `:id`
+fn sqlite_bind_param<I: sqlite::ParameterIndex + Copy>(statement: &mut sqlite::Statement, i: I, param: ServValue, scope: &Stack) -> Result<(), ServError> {
     match param {
-        ServValue::Ref(ref addr) => sqlite_bind_param(statement, i, crate::engine::deref(addr, scope)?, scope)?,
-        ServValue::Func(_)   => {
+        ServValue::Ref(_) | ServValue::Func(_) => {
             let result = engine::resolve(param, None, scope)?;
-            sqlite_bind_param(statement, i, result, scope)?
+            return sqlite_bind_param(statement, i, result, scope)
         },
-        ServValue::Int(v)    => statement.bind((i, v)).unwrap(),
-        ServValue::Text(t)   => statement.bind((i, t.as_str()?)).unwrap(),
-        ServValue::Float(v)  => statement.bind((i, v)).unwrap(),
-        ServValue::None      => statement.bind((i, ())).unwrap(),
-        ServValue::Bool(v)   => todo!(),
-        ServValue::List(v)   => todo!(),
-        ServValue::Table(v)  => todo!(),
-
-        ref otherwise => panic!("{:?}", param),
-    };
+
+        ServValue::Int(v)    => statement.bind((i, v)),
+        ServValue::Float(v)  => statement.bind((i, v)),
+        ServValue::Bool(v)   => statement.bind((i, v as i64)),
+        ServValue::None      => statement.bind((i, ())),
+        // blobs stay blobs, even when their bytes are valid utf-8
+        ServValue::Text(ref t) if t.is_str() => statement.bind((i, t.as_str()?)),
+        ServValue::Text(ref t) => statement.bind((i, t.as_bytes())),
+
+        ref otherwise => return Err(ServError::new(500, &format!("a {} can't be used as a query parameter", ServType::from(otherwise)))),
+    }?;
 
     Ok(())
 }
 
-fn sqlite_query(mut arg: ServValue, input: ServValue, s: &Stack) -> ServResult {
-    let DatabaseConnection::Sqlite(connection) = s.get_database_connection().unwrap();
+/// Bind each field of a table to the parameter with the same name, ie. `:id` or `$id`
+fn sqlite_bind_named(statement: &mut sqlite::Statement, table: HashMap<String, ServValue>, scope: &Stack) -> Result<(), ServError> {
+    for (key, value) in table {
+        for prefix in [":", "@", "$"] {
+            let name = format!("{}{}", prefix, key);
+            if statement.parameter_index(&name)?.is_some() {
+                sqlite_bind_param(statement, name.as_str(), value.clone(), scope)?;
+            }
+        }
+    }
+
+    Ok(())
+}
 
-    let mut child = s.make_child();
-    child.insert("in", input.clone());
-    child.insert("x", input);
-    let scope = &child;
+/// A query template rendered into sql, along with the values to bind to it
+struct Query {
+    text: String,
+    params: Vec<ServValue>,
+    named: Option<HashMap<String, ServValue>>,
+}
 
-	while let ServValue::Ref(addr) = arg {
-    	arg = crate::engine::deref(&addr, scope)?;
-	}
+impl Query {
+    /// Render a query template, with the input to the query bound to `in` and `x`
+    fn render(mut arg: ServValue, input: ServValue, s: &Stack) -> Result<Self, ServError> {
+        // a table given as input is bound by name, ie. `query {select * from users where id = :id}`
+        let named = match input {
+            ServValue::Table(ref table) => Some(table.clone()),
+            _ => None,
+        };
 
-	let ServValue::Func(ServFn::Template(t)) = arg else {panic!()};
+        let mut child = s.make_child();
+        child.insert("in", input.clone())?;
+        child.insert("x", input)?;
+        let scope = &child;
 
-    let mut r = SqliteRenderer(Vec::new());
-    let mut query = String::new();
-    r.render(&t, &mut query);
+    	while let ServValue::Ref(addr) = arg {
+        	arg = crate::engine::deref(&addr, scope)?;
+    	}
 
-    let mut statement = connection.prepare(query).unwrap();
-    for (mut i, p) in r.0.into_iter().enumerate() {
-        i += 1;
-        sqlite_bind_param(&mut statement, i, p, scope).unwrap();
+    	let ServValue::Func(ServFn::Template(t)) = arg else {
+        	return Err(ServError::new(500, "sqlite.query expects a query template, ie. {select * from users;}"))
+    	};
+
+        let mut r = SqliteRenderer { params: Vec::new(), scope };
+        let mut text = String::new();
+        r.render(&t, &mut text)?;
+
+        Ok(Self { text, params: r.params, named })
     }
 
+    /// Wrap the query in another one, ie. `select count(*) from ({})`
+    fn wrap(&self, outer: &str) -> Self {
+        let inner = self.text.trim().trim_end_matches(';');
+        Self { text: outer.replace("{}", inner), params: self.params.clone(), named: self.named.clone() }
+    }
 
-    let mut output: Vec<ServValue> = Vec::new();
-    while let Ok(sqlite::State::Row) = statement.next() {
-        let mut row: HashMap<String, ServValue> = HashMap::new();
-        for (index, name) in statement.column_names().iter().enumerate() {
-            let value = match statement.column_type(index).unwrap() {
-                sqlite::Type::Binary  => {
-                    let v: i64 = statement.read(index).unwrap();
-                    ServValue::Bool(if v == 0 {false} else {true})
-                },
-                sqlite::Type::Float   => ServValue::Float(statement.read(index).unwrap()),
-                sqlite::Type::Integer => ServValue::Int(statement.read(index).unwrap()),
-                sqlite::Type::Null    => ServValue::None,
-                sqlite::Type::String  => statement.read::<String, _>(index).unwrap().into(),
+    fn prepare<'c>(&self, connection: &'c sqlite::Connection, scope: &Stack) -> Result<sqlite::Statement<'c>, ServError> {
+        let mut statement = connection.prepare(&self.text)?;
+        if let Some(ref table) = self.named {
+            sqlite_bind_named(&mut statement, table.clone(), scope)?;
+        }
 
-            };
-			row.insert(name.clone(), value);
+        // bound after the table, so that a field that happens to share a name can't replace them
+        for (i, p) in self.params.iter().enumerate() {
+            sqlite_bind_param(&mut statement, placeholder(i + 1).as_str(), p.clone(), scope)?;
         }
-        output.push(ServValue::Table(row));
+
+        Ok(statement)
+    }
+}
+
+fn read_row(statement: &sqlite::Statement) -> Result<ServValue, ServError> {
+    let mut row: HashMap<String, ServValue> = HashMap::new();
+    for (index, name) in statement.column_names().iter().enumerate() {
+        let value = match statement.column_type(index)? {
+            sqlite::Type::Binary  => ServString::from_binary(statement.read::<Vec<u8>, _>(index)?).as_value(),
+            sqlite::Type::Float   => ServValue::Float(statement.read(index)?),
+            sqlite::Type::Integer => ServValue::Int(statement.read(index)?),
+            sqlite::Type::Null    => ServValue::None,
+            sqlite::Type::String  => statement.read::<String, _>(index)?.into(),
+        };
+		row.insert(name.clone(), value);
+    }
+
+    Ok(ServValue::Table(row))
+}
+
+fn run_query(connection: &sqlite::Connection, query: &Query, scope: &Stack) -> ServResult {
+    let mut statement = query.prepare(connection, scope)?;
+
+    let mut output: Vec<ServValue> = Vec::new();
+    while let sqlite::State::Row = statement.next()? {
+        output.push(read_row(&statement)?);
     }
 
     Ok(ServValue::List(output.into()))
 }
 
+fn sqlite_query(arg: ServValue, input: ServValue, scope: &Stack) -> ServResult {
+    let query = Query::render(arg, input, scope)?;
+    with_connection(scope, |connection| run_query(connection, &query, scope))
+}
+
+/// A link to another page of the current request. If the page number is the last part of the
+/// path, ie. `/users/{n}`, it is replaced, otherwise the `page` query parameter is set.
+fn page_link(current: i64, page: i64, scope: &Stack) -> ServValue {
+    let Some(request) = scope.get_request() else {
+        return ServValue::from(format!("?page={}", page))
+    };
+
+    let path = request.uri.path();
+    if let Some((rest, last)) = path.rsplit_once('/') {
+        if last == current.to_string() {
+            return match request.uri.query() {
+                Some(query) => ServValue::from(format!("{}/{}?{}", rest, page, query)),
+                None => ServValue::from(format!("{}/{}", rest, page)),
+            }
+        }
+    }
+
+    let mut query: Vec<&str> = request.uri.query().unwrap_or("")
+        .split('&')
+        .filter(|p| !p.is_empty() && !p.starts_with("page="))
+        .collect();
+
+    let page = format!("page={}", page);
+    query.push(&page);
+    ServValue::from(format!("{}?{}", path, query.join("&")))
+}
+
+/// Page numbers usually come from the url, so text is accepted as well as ints
+fn get_number(value: ServValue) -> Result<Option<i64>, ServError> {
+    match value {
+        ServValue::None => Ok(None),
+        ServValue::Int(i) => Ok(Some(i)),
+        ServValue::Text(_) => value.to_string().trim().parse().map(Some)
+            .map_err(|_| ServError::new(400, &format!("expected a page number, got {}", value))),
+        otherwise => Ok(Some(otherwise.expect_int()?)),
+    }
+}
+
+/// `sqlite.page n size {...}` returns one page of the results of a query, starting from page 1,
+/// as a table with the `rows` on that page, the `total` number of rows, and links to the `next`
+/// and `prev` pages, which are empty on the last and first pages
+fn sqlite_page(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let page = get_number(input.pop()?.call(None, scope)?)?.unwrap_or(1).max(1);
+    let size = get_number(input.pop()?.call(None, scope)?)?.unwrap_or(0);
+    if size <= 0 {
+        return Err(ServError::new(500, "sqlite.page expects a positive page size"))
+    }
+
+    let template = input.pop()?;
+    let rest = input.eval(scope)?;
+    let query = Query::render(template, rest, scope)?;
+
+    let (offset, end) = match (page - 1).checked_mul(size) {
+        Some(offset) => (offset, offset.checked_add(size)),
+        None => return Err(ServError::new(400, &format!("page {} is out of range", page))),
+    };
+
+    let count = query.wrap("SELECT count(*) FROM ({})");
+    let rows = query.wrap(&format!("SELECT * FROM ({{}}) LIMIT {} OFFSET {}", size, offset));
+
+    with_connection(scope, |connection| {
+        let mut statement = count.prepare(connection, scope)?;
+        statement.next()?;
+        let total = statement.read::<i64, _>(0)?;
+
+        let mut output = HashMap::new();
+        output.insert("rows".to_owned(), run_query(connection, &rows, scope)?);
+        output.insert("total".to_owned(), ServValue::Int(total));
+        output.insert("page".to_owned(), ServValue::Int(page));
+        output.insert("size".to_owned(), ServValue::Int(size));
+
+        let next = if end.is_some_and(|end| end < total) { page_link(page, page + 1, scope) } else { ServValue::None };
+        let prev = if page > 1 { page_link(page, page - 1, scope) } else { ServValue::None };
+        output.insert("next".to_owned(), next);
+        output.insert("prev".to_owned(), prev);
+
+        Ok(ServValue::Table(output))
+    })
+}
+
+/// `sqlite.stream {...}` sends the results of a query as a json array, writing each row as it is
+/// read rather than building the whole result in memory first. The webserver runs the query once
+/// the route has finished evaluating, see `stream_query`.
+fn sqlite_stream(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let template = input.pop()?;
+    let rest = input.eval(scope)?;
+
+    scope.insert("res.stream.query", template)?;
+    scope.insert("res.stream.input", rest)?;
+    Ok(ServValue::None)
+}
+
+/// Run a query set up by `sqlite.stream`, calling `send` with each chunk of the json output
+/// until it returns false
+pub fn stream_query(config: ServModule, scope: &Stack, mut send: impl FnMut(String) -> bool) -> Result<(), ServError> {
+    let template = config.values.get(&"query".into()).cloned().ok_or("invalid stream")?;
+    let input = config.values.get(&"input".into()).cloned().unwrap_or_default();
+    let query = Query::render(template, input, scope)?;
+
+    // a slow client would otherwise keep a connection out of the pool for as long as it takes
+    if let Some(connection) = get_pool(scope)?.connect_dedicated()? {
+        return send_rows(&connection, &query, scope, &mut send)
+    }
+
+    // an in memory database is already in memory, so its rows can be read before sending any
+    let mut chunks = Vec::new();
+    with_connection(scope, |connection| send_rows(connection, &query, scope, &mut |chunk| { chunks.push(chunk); true }))?;
+    for chunk in chunks {
+        if !send(chunk) { break };
+    }
+
+    Ok(())
+}
+
+fn send_rows(connection: &sqlite::Connection, query: &Query, scope: &Stack, send: &mut impl FnMut(String) -> bool) -> Result<(), ServError> {
+    let mut statement = query.prepare(connection, scope)?;
+    let mut separator = "[";
+
+    while let sqlite::State::Row = statement.next()? {
+        let mut chunk = separator.to_owned();
+        DefaultSerializer(scope).write(read_row(&statement)?, &mut chunk)?;
+        if !send(chunk) { return Ok(()) };
+        separator = ",";
+    }
+
+    send(if separator == "[" { "[]" } else { "]" }.to_owned());
+    Ok(())
+}
+
+/// Start a transaction on the selected database, nested inside of the one already in progress if there is one
+pub(super) fn begin_transaction(scope: &Stack) -> Result<Arc<Transaction>, ServError> {
+    let pool = get_pool(scope)?;
+    let outer = scope.get_transaction().filter(|t| Arc::ptr_eq(&t.pool, &pool));
+    Ok(Arc::new(Transaction::begin(pool, outer.map(|t| t.as_ref()))?))
+}
+
+/// `sqlite.transaction (...)` runs each statement of a module inside of a transaction, which
+/// is committed if they all succeed and rolled back as soon as one of them fails. Transactions
+/// can be nested, in which case the inner one becomes a savepoint.
+fn sqlite_transaction(arg: ServValue, input: ServValue, scope: &Stack) -> ServResult {
+    let m = arg.expect_module()?;
+    let transaction = begin_transaction(scope)?;
+
+    let mut child = scope.make_child();
+    child.transaction = Some(transaction.clone());
+    child.insert_module(m.values);
+
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut output = ServValue::None;
+        for mut expr in m.statements {
+            output = expr.eval(&mut child)?;
+        }
+
+        Ok(output)
+    }));
+
+    match result {
+        Ok(result) => transaction.finish(result),
+
+        // don't hand the connection back to the pool in the middle of a transaction
+        Err(panic) => {
+            transaction.abort();
+            std::panic::resume_unwind(panic)
+        },
+    }
+}
+
 use crate::engine;
 
+/// Read the options module that can be given to `sqlite.connect` before the file name,
+/// returning the name of the connection separately from the options for its pool
+fn get_pool_options(options: ServModule, scope: &Stack) -> Result<(Option<String>, PoolOptions), ServError> {
+    let mut name = None;
+    let mut output = PoolOptions::default();
+    for (key, value) in options.values {
+        let value = value.call(None, scope)?;
+        match key.as_str() {
+            "name"         => name = Some(value.to_string()),
+            "pool_size"    => output.size = value.expect_int()?.try_into().map_err(|_| "invalid pool size")?,
+            "readonly"     => output.readonly = value.is_truthy(),
+            "busy_timeout" => output.busy_timeout = Duration::from_millis(value.expect_int()?.try_into().map_err(|_| "invalid busy timeout")?),
+            other => return Err(ServError::new(500, &format!("unknown sqlite.connect option: {}", other))),
+        }
+    }
+
+    if name.as_deref().is_some_and(|n| n.is_empty() || n.contains(char::is_whitespace)) {
+        return Err("sqlite.connect expects the name of a connection to be a single word".into());
+    }
+
+    Ok((name, output))
+}
+
+/// `sqlite.connect (name = {app}, pool_size = 8, readonly = true) {file}` opens a pool of
+/// connections to a database. The options are optional. Named databases are selected with
+/// `using db.name`, or `query@name`, and the first database opened is also the default.
 fn sqlite_connect(mut input: ServList, ctx: &mut Stack) -> ServResult {
+    let (name, options) = match input.get(0) {
+        Ok(ServValue::Func(ServFn::SubExpression(_))) | Ok(ServValue::Module(_)) => {
+            get_pool_options(input.pop()?.expect_module()?, ctx)?
+        },
+        _ => (None, PoolOptions::default()),
+    };
+
     let location = engine::eval(input, ctx)?.to_string();
-    let connection = sqlite::Connection::open_thread_safe(location.as_str()).unwrap();
+    let pool = SqlitePool::open(&location, options)?;
+
+    let connection = DatabaseConnection::Sqlite(Arc::new(pool));
+
+    if ctx.get_database_connection().is_none() || name.is_none() {
+        ctx.connection = Some(connection.clone());
+    }
+
+    if let Some(name) = name {
+        let mut selector = ServModule::empty();
+        selector.values.insert(Label::Name(SELECTED_DATABASE.to_owned()), ServValue::from(name.clone()));
+        ctx.insert(format!("db.{}", name).as_str(), selector.into())?;
+        ctx.connections.insert(name, connection);
+    }
 
-    ctx.connection = Some(DatabaseConnection::Sqlite(connection));
     Ok(ServValue::None)
 }
 
@@ -143,5 +489,255 @@ pub fn get_module() -> ServModule {
 	output.insert("sqlite.connect", ServFn::Meta(sqlite_connect).into());
 	output.insert("sqlite.query",   ServFn::ArgFn(sqlite_query).into());
 	output.insert("sqlite.run",     ServFn::Core(sqlite_exec).into());
+	output.insert("sqlite.transaction", ServFn::ArgFn(sqlite_transaction).into());
+	output.insert("sqlite.page",    ServFn::Meta(sqlite_page).into());
+	output.insert("sqlite.stream",  ServFn::Meta(sqlite_stream).into());
+	output.insert("sqlite.migrate", ServFn::Core(super::migrate::sqlite_migrate).into());
+	output.insert(USING_DATABASE,   ServFn::Meta(using_database).into());
 	output
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluate the statements of some serv source, returning the result of the last one
+    fn run(source: &str) -> ServResult {
+        let mut scope = Stack::empty();
+        scope.insert_module(crate::functions::standard_library().values);
+        let module = crate::parser::parse_root_from_text(source, &mut scope)?;
+        scope.insert_module(module.values.clone());
+
+        let mut output = ServValue::None;
+        for expr in module.statements {
+            output = engine::eval(expr, &mut scope)?;
+        }
+
+        Ok(output)
+    }
+
+    /// One column of the rows returned by a query, as text
+    fn column(output: ServValue, name: &str) -> Vec<String> {
+        let ServValue::List(rows) = output else { panic!("expected a list of rows, got {}", output) };
+        rows.map(|row| match row {
+            ServValue::Table(mut row) => row.remove(name).unwrap().to_string(),
+            otherwise => panic!("expected a row, got {}", otherwise),
+        }).collect()
+    }
+
+    #[test]
+    fn named_connections() {
+        let output = run("
+            sqlite.connect (name = {main}) {:memory:}
+            sqlite.connect (name = {other}) {:memory:}
+            sqlite.run {create table t (x); insert into t values ('main');}
+            sqlite.run@other {create table t (x); insert into t values ('other');}
+            sqlite.query@other {select x from t;}
+        ").unwrap();
+        assert_eq!(column(output, "x"), ["other"]);
+
+        // the first database opened is the default
+        let output = run("
+            sqlite.connect (name = {main}) {:memory:}
+            sqlite.connect (name = {other}) {:memory:}
+            sqlite.run@main {create table t (x); insert into t values ('main');}
+            sqlite.run@other {create table t (x); insert into t values ('other');}
+            sqlite.query {select x from t;}
+        ").unwrap();
+        assert_eq!(column(output, "x"), ["main"]);
+
+        let output = run("
+            sqlite.connect (name = {main}) {:memory:}
+            sqlite.connect (name = {other}) {:memory:}
+            sqlite.run@other {create table t (x); insert into t values ('other');}
+            using db.other sqlite.query {select x from t;}
+        ").unwrap();
+        assert_eq!(column(output, "x"), ["other"]);
+
+        assert!(run("sqlite.connect {:memory:}\nsqlite.query@missing {select 1;}").is_err());
+        assert!(run("sqlite.connect (name = {two words}) {:memory:}").is_err());
+    }
+
+    #[test]
+    fn transactions_roll_back_on_error() {
+        let output = run("
+            sqlite.connect {:memory:}
+            sqlite.run {create table t (x);}
+            try (
+                sqlite.transaction (
+                    sqlite.run {insert into t values (1);}
+                    sqlite.run {not sql;}
+                ),
+                {caught}
+            )
+        ").unwrap();
+        assert_eq!(output.to_string(), "caught");
+
+        let output = run("
+            sqlite.connect {:memory:}
+            sqlite.run {create table t (x);}
+            sqlite.transaction (
+                sqlite.run {insert into t values (1);}
+                sqlite.run {insert into t values (2);}
+            )
+            sqlite.query {select x from t;}
+        ").unwrap();
+        assert_eq!(column(output, "x"), ["1", "2"]);
+    }
+
+    #[test]
+    fn transactions_return_the_error_that_ended_them() {
+        let error = run("
+            sqlite.connect {:memory:}
+            sqlite.transaction (
+                sqlite.run {not sql;}
+            )
+        ").unwrap_err();
+        assert!(error.to_string().contains("syntax error"), "{}", error);
+    }
+
+    #[test]
+    fn nested_transactions_roll_back_to_a_savepoint() {
+        let output = run("
+            sqlite.connect {:memory:}
+            sqlite.run {create table t (x);}
+            sqlite.transaction (
+                sqlite.run {insert into t values ('outer');}
+                try (
+                    sqlite.transaction (
+                        sqlite.run {insert into t values ('inner');}
+                        sqlite.run {not sql;}
+                    ),
+                    {caught}
+                )
+                sqlite.run {insert into t values ('after');}
+            )
+            sqlite.query {select x from t;}
+        ").unwrap();
+        assert_eq!(column(output, "x"), ["outer", "after"]);
+    }
+
+    #[test]
+    fn failed_commits_are_rolled_back() {
+        // deferred foreign keys are checked on commit, so this transaction fails to commit
+        let output = run("
+            sqlite.connect {:memory:}
+            sqlite.run {
+                pragma foreign_keys = on;
+                create table parent (id integer primary key);
+                create table child (parent_id references parent (id) deferrable initially deferred);
+            }
+            try (
+                sqlite.transaction (
+                    sqlite.run {insert into child values (1);}
+                ),
+                {caught}
+            )
+            sqlite.query {select count(*) as n from child;}
+        ").unwrap();
+        assert_eq!(column(output, "n"), ["0"]);
+    }
+
+    #[test]
+    fn named_and_expression_params() {
+        // sqlite numbers `:name` 1 and the `?` after it 2, so binding `$age` to 1 lost it
+        let output = run(r#"
+            age = 30
+            sqlite.connect {:memory:}
+            sqlite.run {create table t (name, age);}
+            sqlite.query {insert into t values (:name, $age);} json {{"name": "alice"}}
+            sqlite.query {select name, age from t where name = :name and age = $age;} json {{"name": "alice"}}
+        "#).unwrap();
+        assert_eq!(column(output.clone(), "name"), ["alice"]);
+        assert_eq!(column(output, "age"), ["30"]);
+
+        // a field that shares a name with a placeholder doesn't replace its value
+        let output = run(r#"
+            age = 30
+            sqlite.connect {:memory:}
+            sqlite.query {select $age as age;} json {{"_serv_1": 99}}
+        "#).unwrap();
+        assert_eq!(column(output, "age"), ["30"]);
+    }
+
+    #[test]
+    fn lists_expand_into_in_clauses() {
+        let output = run("
+            ids = list (1, 3)
+            sqlite.connect {:memory:}
+            sqlite.run {create table t (x); insert into t values (1), (2), (3);}
+            sqlite.query {select x from t where x in ($ids) order by x;}
+        ").unwrap();
+        assert_eq!(column(output, "x"), ["1", "3"]);
+
+        // an empty list matches nothing, instead of being a syntax error
+        let output = run("
+            ids = list ()
+            sqlite.connect {:memory:}
+            sqlite.run {create table t (x); insert into t values (1);}
+            sqlite.query {select x from t where x in ($ids);}
+        ").unwrap();
+        assert!(column(output, "x").is_empty());
+    }
+
+    #[test]
+    fn binds_values_of_each_type() {
+        let output = run("
+            i = 5
+            t = {hi}
+            b = true
+            sqlite.connect {:memory:}
+            sqlite.query {select $i as i, $t as t, $b as b;}
+        ").unwrap();
+        let ServValue::List(mut rows) = output else { panic!("expected a list of rows") };
+        let Some(ServValue::Table(row)) = rows.next() else { panic!("expected a row") };
+        assert!(matches!(row["i"], ServValue::Int(5)));
+        assert_eq!(row["t"].to_string(), "hi");
+        assert!(matches!(row["b"], ServValue::Int(1)));
+    }
+
+    #[test]
+    fn blobs_round_trip_as_blobs() {
+        // the bytes of a blob are valid utf-8 here, which used to turn it into text
+        let output = run("
+            sqlite.connect {:memory:}
+            sqlite.query {select x'68690a' as b;}
+        ").unwrap();
+        let ServValue::List(mut rows) = output else { panic!("expected a list of rows") };
+        let Some(ServValue::Table(mut row)) = rows.next() else { panic!("expected a row") };
+        let blob = row.remove("b").unwrap();
+
+        let mut scope = Stack::empty();
+        scope.insert_module(crate::functions::standard_library().values);
+        scope.insert("blob", blob).unwrap();
+        let module = crate::parser::parse_root_from_text("
+            sqlite.connect {:memory:}
+            sqlite.query {select typeof($blob) as type, hex($blob) as hex;}
+        ", &mut scope).unwrap();
+
+        let mut output = ServValue::None;
+        for expr in module.statements {
+            output = engine::eval(expr, &mut scope).unwrap();
+        }
+
+        assert_eq!(column(output.clone(), "type"), ["blob"]);
+        assert_eq!(column(output, "hex"), ["68690A"]);
+    }
+
+    #[test]
+    fn constraint_violations_are_conflicts() {
+        let error = run("
+            sqlite.connect {:memory:}
+            sqlite.run {create table users (name unique); insert into users values ('alice');}
+            sqlite.run {insert into users values ('alice');}
+        ").unwrap_err();
+        assert_eq!(error.status(), 409);
+
+        let error = run("
+            sqlite.connect {:memory:}
+            sqlite.run {not sql;}
+        ").unwrap_err();
+        assert!(matches!(error, ServError::Sqlite { code: Some(_), .. }));
+        assert_eq!(error.status(), 500);
+    }
+}
@@ -0,0 +1,258 @@
+//! a process wide key value store that persists between requests
+
+use crate::{ServValue, ServResult, ServError, Stack, ServFn, ServModule};
+use crate::value::ServList;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Condvar, Mutex, MutexGuard, LazyLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+struct Entry {
+    value: ServValue,
+    expires: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|t| t <= Instant::now())
+    }
+}
+
+static STATE: LazyLock<Mutex<HashMap<String, Entry>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Expired entries are swept out on write once there are this many entries, which then doubles
+/// so that sweeping stays cheap as the store grows
+static SWEEP_AT: AtomicUsize = AtomicUsize::new(MIN_SWEEP);
+const MIN_SWEEP: usize = 1024;
+
+/// Keys that have an update in progress, so that updates to the same key never interleave
+static LOCKED_KEYS: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+static KEY_RELEASED: Condvar = Condvar::new();
+
+thread_local! {
+    /// The key that the current thread is running an update of, if any
+    static UPDATING: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Marks the current thread as running an update of a key, until dropped
+struct Updating;
+
+impl Updating {
+    fn start(key: &str) -> Result<Self, ServError> {
+        UPDATING.with_borrow_mut(|updating| match updating {
+            Some(_) => Err(ServError::new(500, "state.update cannot be nested")),
+            None => { *updating = Some(key.to_owned()); Ok(Self) },
+        })
+    }
+}
+
+impl Drop for Updating {
+    fn drop(&mut self) {
+        UPDATING.set(None);
+    }
+}
+
+/// A key that no other thread can update or write to until this is dropped
+struct KeyLock(String);
+
+impl KeyLock {
+    fn acquire(key: &str) -> Self {
+        let mut locked = lock(&LOCKED_KEYS);
+        while locked.contains(key) {
+            locked = KEY_RELEASED.wait(locked).unwrap_or_else(|e| e.into_inner());
+        }
+
+        locked.insert(key.to_owned());
+        Self(key.to_owned())
+    }
+}
+
+impl Drop for KeyLock {
+    fn drop(&mut self) {
+        lock(&LOCKED_KEYS).remove(&self.0);
+        KEY_RELEASED.notify_all();
+    }
+}
+
+/// Wait for any update of a key in progress on another thread, so that writes can't land
+/// between the read and write of an update. An update can write to its own key without waiting,
+/// but not to other keys, since those could be in the middle of an update on another thread.
+fn exclusive(key: &str) -> Result<Option<KeyLock>, ServError> {
+    UPDATING.with_borrow(|updating| match updating {
+        None => Ok(Some(KeyLock::acquire(key))),
+        Some(held) if held == key => Ok(None),
+        Some(held) => Err(ServError::new(500, &format!("state.update {} can't write to {} while it runs", held, key))),
+    })
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Read a key, treating expired entries as missing
+fn read(state: &mut HashMap<String, Entry>, key: &str) -> ServValue {
+    if state.get(key).is_some_and(|e| e.is_expired()) {
+        state.remove(key);
+    }
+
+    state.get(key).map(|e| e.value.clone()).unwrap_or(ServValue::None)
+}
+
+/// Write a key, keeping its expiry unless a new one is given
+fn write(state: &mut HashMap<String, Entry>, key: String, value: ServValue, ttl: Option<Duration>) {
+    let expires = match ttl {
+        Some(ttl) => Some(Instant::now() + ttl),
+        None => state.get(&key).filter(|e| !e.is_expired()).and_then(|e| e.expires),
+    };
+
+    state.insert(key, Entry { value, expires });
+
+    // keys that are written once with a ttl and never read again would otherwise stay forever
+    if state.len() >= SWEEP_AT.load(Ordering::Relaxed) {
+        state.retain(|_, e| !e.is_expired());
+        SWEEP_AT.store((state.len() * 2).max(MIN_SWEEP), Ordering::Relaxed);
+    }
+}
+
+fn get_ttl(value: ServValue) -> Result<Duration, ServError> {
+    let seconds = value.expect_int()?;
+    if seconds <= 0 {
+        return Err(ServError::new(500, "expected a positive number of seconds"))
+    }
+
+    Ok(Duration::from_secs(seconds as u64))
+}
+
+fn state_get(input: ServValue, scope: &Stack) -> ServResult {
+    Ok(read(&mut lock(&STATE), &input.to_string()))
+}
+
+fn state_set(arg: ServValue, input: ServValue, scope: &Stack) -> ServResult {
+    let key = arg.call(None, scope)?.to_string();
+    let _guard = exclusive(&key)?;
+    write(&mut lock(&STATE), key, input.clone(), None);
+    Ok(input)
+}
+
+/// `state.ttl seconds key value` stores a value that is forgotten after a number of seconds
+fn state_ttl(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let ttl = get_ttl(input.pop()?.call(None, scope)?)?;
+    let key = input.pop()?.call(None, scope)?.to_string();
+    let value = input.eval(scope)?;
+
+    let _guard = exclusive(&key)?;
+    write(&mut lock(&STATE), key, value.clone(), Some(ttl));
+    Ok(value)
+}
+
+/// Add one to a counter, starting from 0 if it isn't set, and return the new count
+fn state_incr(input: ServValue, scope: &Stack) -> ServResult {
+    let key = input.to_string();
+    let _guard = exclusive(&key)?;
+    let mut state = lock(&STATE);
+
+    let count = match read(&mut state, &key) {
+        ServValue::None => 1,
+        value => value.expect_int()? + 1,
+    };
+
+    write(&mut state, key, ServValue::Int(count), None);
+    Ok(ServValue::Int(count))
+}
+
+fn state_delete(input: ServValue, scope: &Stack) -> ServResult {
+    let key = input.to_string();
+    let _guard = exclusive(&key)?;
+    let removed = lock(&STATE).remove(&key);
+    Ok(removed.filter(|e| !e.is_expired()).map(|e| e.value).unwrap_or(ServValue::None))
+}
+
+/// `state.update key expr` calls expr with the current value of key (or None), and stores the
+/// result. No other update or write to the same key can happen in between, so read-modify-write
+/// cycles don't race, while updates to other keys run at the same time. The expression can only
+/// write to the key being updated.
+fn state_update(mut input: ServList, scope: &mut Stack) -> ServResult {
+    let key = input.pop()?.call(None, scope)?.to_string();
+
+    let _updating = Updating::start(&key)?;
+    let _guard = KeyLock::acquire(&key);
+
+    let current = read(&mut lock(&STATE), &key);
+    let value = input.as_expr().call(Some(current), scope)?;
+    write(&mut lock(&STATE), key, value.clone(), None);
+    Ok(value)
+}
+
+pub fn get_module() -> ServModule {
+    let mut output = ServModule::empty();
+	output.insert("state.get",     ServFn::Core(state_get).into());
+	output.insert("state.set",     ServFn::ArgFn(state_set).into());
+	output.insert("state.ttl",     ServFn::Meta(state_ttl).into());
+	output.insert("state.incr",    ServFn::Core(state_incr).into());
+	output.insert("state.delete",  ServFn::Core(state_delete).into());
+	output.insert("state.update",  ServFn::Meta(state_update).into());
+	output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine;
+
+    /// Evaluate the statements of some serv source, returning the result of the last one
+    fn run(source: &str) -> ServResult {
+        let mut scope = Stack::empty();
+        scope.insert_module(crate::functions::standard_library().values);
+        let module = crate::parser::parse_root_from_text(source, &mut scope)?;
+        scope.insert_module(module.values.clone());
+
+        let mut output = ServValue::None;
+        for expr in module.statements {
+            output = engine::eval(expr, &mut scope)?;
+        }
+
+        Ok(output)
+    }
+
+    #[test]
+    fn set_incr_and_delete() {
+        run("state.set {test.a} {hello}").unwrap();
+        assert_eq!(run("state.get {test.a}").unwrap().to_string(), "hello");
+
+        assert!(matches!(run("state.incr {test.count}").unwrap(), ServValue::Int(1)));
+        assert!(matches!(run("state.incr {test.count}").unwrap(), ServValue::Int(2)));
+
+        assert_eq!(run("state.delete {test.a}").unwrap().to_string(), "hello");
+        assert!(matches!(run("state.get {test.a}").unwrap(), ServValue::None));
+    }
+
+    #[test]
+    fn expired_keys_are_missing() {
+        write(&mut lock(&STATE), "test.expired".to_owned(), ServValue::Int(1), Some(Duration::ZERO));
+        assert!(matches!(run("state.get {test.expired}").unwrap(), ServValue::None));
+    }
+
+    #[test]
+    fn updates_can_only_write_their_own_key() {
+        assert!(matches!(run("state.update {test.own} state.set {test.own} 5").unwrap(), ServValue::Int(5)));
+        assert!(run("state.update {test.one} state.set {test.other} 5").is_err());
+        assert!(matches!(run("state.get {test.other}").unwrap(), ServValue::None));
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        run("state.set {test.concurrent} 0").unwrap();
+
+        let threads: Vec<_> = (0..8).map(|_| std::thread::spawn(|| {
+            for _ in 0..25 {
+                run("state.update {test.concurrent} +").unwrap();
+                run("state.incr {test.concurrent}").unwrap();
+            }
+        })).collect();
+
+        for thread in threads { thread.join().unwrap() };
+        assert!(matches!(run("state.get {test.concurrent}").unwrap(), ServValue::Int(400)));
+    }
+}
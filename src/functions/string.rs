@@ -9,23 +9,17 @@ fn htmx_src(_input: ServValue, _scope: &Stack) -> ServResult {
     Ok(ServString::from(HTMX_SRC.as_slice()).into())
 }
 
+/// Mark text as trusted html, so that html templates insert it without escaping it
+fn raw(input: ServValue, scope: &Stack) -> ServResult {
+    match input {
+        ServValue::Text(t) => Ok(t.mark_safe().as_value()),
+        otherwise => Ok(ServString::from(otherwise.to_string()).mark_safe().as_value()),
+    }
+}
 
-/// Convert markdown text into HTML
-fn markdown(input: ServValue, scope: &Stack) -> ServResult {
-    let compile_options = markdown::CompileOptions {
-        allow_dangerous_html: true,
-        allow_dangerous_protocol: true,
-        gfm_tagfilter: false,
-        ..markdown::CompileOptions::default()
-    };
-
-    let options = markdown::Options {
-        compile: compile_options,
-        ..markdown::Options::gfm()
-    };
-
-    let output = markdown::to_html_with_options(input.to_string().as_str(), &options).unwrap();
-    Ok(ServValue::Text(output.into()))
+/// Escape text for html, for templates that aren't escaped automatically
+fn escape(input: ServValue, scope: &Stack) -> ServResult {
+    Ok(crate::template::escape_html(&input.to_string()).into())
 }
 
 
@@ -36,7 +30,9 @@ fn lorem(input: ServValue, scope: &Stack) -> ServResult {
 
 pub fn get_module() -> ServModule {
     let mut output = ServModule::empty();
-	output.insert("markdown", ServFn::Core(markdown).into());
+	output.insert("raw", ServFn::Core(raw).into());
+	output.insert("safe", ServFn::Core(raw).into());
+	output.insert("escape", ServFn::Core(escape).into());
 	output.insert("lorem", ServFn::Core(lorem).into());
 	output.insert("jslib.htmx", ServFn::Core(htmx_src).into());
     output
@@ -5,6 +5,7 @@ mod engine;
 mod error;
 mod functions;
 mod webserver;
+mod watch;
 
 pub use engine::datatypes;
 
@@ -17,13 +18,13 @@ use engine::dictionary::Stack;
 use datatypes::reference::Address;
 use error::ServError;
 use engine::dictionary::Label;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use matchit::Router;
 
 type ServResult = Result<ServValue, ServError>;
 
 /// A parser for serv files
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about)]
 struct CliArgs {
 
@@ -35,12 +36,49 @@ struct CliArgs {
     #[arg(long)]
     host: Option<String>,
 
+	/// An address to listen on instead of --host and --port, ie. `https://[::]:443` or
+	/// `unix:/run/serv.sock`. Can be given more than once to listen on several addresses.
+    #[arg(short, long)]
+    listen: Vec<String>,
+
 	/// Pass serv code directly as an argument, rather than specifying a file
 	#[arg(short, long)]
 	execute: Vec<String>,
 
+	/// Reload the server whenever one of its source files changes
+	#[arg(short, long)]
+	watch: bool,
+
+	/// Like --watch, but also reload any open browser tabs when a source file or a served file changes
+	#[arg(long)]
+	dev: bool,
+
 	/// The files to parse
     path: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+	/// Manage database migrations
+	Migrate {
+    	#[command(subcommand)]
+    	action: MigrateCommand,
+	},
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum MigrateCommand {
+	/// Show which migrations in a directory have been applied to a database
+	Status {
+    	/// The sqlite database file
+    	database: String,
+
+    	/// The directory of migrations
+    	directory: String,
+	},
 }
 
 fn get_input(args: &mut CliArgs) -> Result<String, ServError> {
@@ -50,7 +88,7 @@ fn get_input(args: &mut CliArgs) -> Result<String, ServError> {
         output.push_str("\n");
     }
 
-    if args.path.len() == 0 && args.execute.len() == 0 {
+    if args.path.is_empty() && args.execute.is_empty() {
         args.path.push("main.serv".into());
     }
 
@@ -69,29 +107,72 @@ fn populate_defaults(scope: &mut Stack, args: &CliArgs) {
         let port: i64 = args.port.into();
         scope.insert("server.port", port.into());
     }
+
+    if let Some(ref host) = args.host {
+        if engine::resolve_key("server.host", scope).is_err() {
+            scope.insert("server.host", host.clone().into());
+        }
+    }
+
+    if !args.listen.is_empty() && engine::resolve_key("server.listen", scope).is_err() {
+        let addresses: ServList = args.listen.iter().map(|l| l.clone().into()).collect();
+        scope.insert("server.listen", addresses.into());
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    let mut args = CliArgs::parse();
-    let input = get_input(&mut args).unwrap();
+/// Parse the input files and run their statements, producing everything
+/// the webserver needs to start responding to requests
+fn load(args: &mut CliArgs) -> Result<webserver::App, ServError> {
+    let input = get_input(args)?;
 
     let mut scope = Stack::empty();
     scope.insert_module(functions::standard_library().values);
 
-    let root_module = parser::parse_root_from_text(&input, &mut scope).unwrap();
+    let root_module = parser::parse_root_from_text(&input, &mut scope)?;
     scope.insert_module(root_module.values.clone());
 
-    populate_defaults(&mut scope, &args);
+    populate_defaults(&mut scope, args);
 
     for expr in &root_module.statements {
-        engine::eval(expr.clone(), &mut scope).unwrap();
+        engine::eval(expr.clone(), &mut scope)?;
+    }
+
+    webserver::App::new(scope, &root_module)
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = CliArgs::parse();
+
+    if let Some(Command::Migrate { action: MigrateCommand::Status { ref database, ref directory } }) = args.command {
+        if let Err(e) = functions::print_migration_status(database, directory) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return
     }
 
-    let mut router = Router::new();
-    for (route, list) in root_module.routes() {
-        router.insert(route, list.clone());
+    if args.dev {
+        args.watch = true;
+        webserver::livereload::enable();
     }
 
-    webserver::run_webserver(scope, router).await;
+    if args.watch { functions::record_files_read() };
+    let app = match load(&mut args) {
+        Ok(app) => app,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
+    let shared = std::sync::Arc::new(std::sync::RwLock::new(std::sync::Arc::new(app)));
+
+    if args.watch {
+        tokio::task::spawn(watch::reload_on_change(args.clone(), shared.clone()));
+    }
+
+    if let Err(e) = webserver::run_webserver(shared).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
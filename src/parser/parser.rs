@@ -61,10 +61,23 @@ fn constant(parser: &mut Parser, ctx: &mut Stack) -> Result<ServValue, ServError
 	return engine::eval(expr, ctx)
 }
 
+/// `func@name` runs func with the database connection opened by
+/// `sqlite.connect (name = {name}) {file}`, like `using db.name func`
+fn with_database(func: &str, name: &str) -> ServValue {
+    let mut expr = ServList::new();
+    expr.push_back(ServValue::Ref(crate::functions::USING_DATABASE.into()));
+    expr.push_back(ServValue::Ref(format!("db.{}", name).as_str().into()));
+    expr.push_back(ServValue::Ref(func.into()));
+    expr.as_expr()
+}
+
 fn parse_word(parser: &mut Parser, ctx: &mut Stack) -> Result<ServValue, ServError> {
     let token = parser.get(0)?;
     let output = match token.kind {
-        TokenKind::Identifier   => ServValue::Ref(token.to_string().as_str().into()),
+        TokenKind::Identifier   => match token.to_string().split_once('@') {
+            Some((func, name)) if !name.is_empty() => with_database(func, name),
+            _ => ServValue::Ref(token.to_string().as_str().into()),
+        },
         TokenKind::Route        => ServValue::Ref(Label::Route(token.to_string()).into()),
         TokenKind::IntLiteral   => ServValue::Int(token.to_string().parse::<i64>().unwrap().into()),
         TokenKind::TemplateOpen => ServValue::Func(ServFn::Template(parse_template(parser)?.into())),
@@ -116,6 +129,7 @@ fn parse_expression(parser: &mut Parser, ctx: &mut Stack) -> Result<ServList, Se
 
 fn get_label(mut input: ServList) -> Result<Address, ServError> {
     if input.len() == 0 { return Err(ServError::new(500, "missing label before declaration")) };
+    if input.len() == 2 { return get_socket_label(input) };
     if input.len() >= 2 { return Err(ServError::new(500, "labels must be exactly 1 word")) };
 
 	match input.pop().unwrap() {
@@ -124,6 +138,20 @@ fn get_label(mut input: ServList) -> Result<Address, ServError> {
 	}
 }
 
+/// `ws /route` declares a handler for websocket connections to a route
+fn get_socket_label(input: ServList) -> Result<Address, ServError> {
+    let invalid = || ServError::new(500, "labels must be exactly 1 word");
+    let (Ok(ServValue::Ref(kind)), Ok(ServValue::Ref(route))) = (input.get(0), input.get(1)) else {
+        return Err(invalid())
+    };
+
+    if *kind != Address::from("ws") || route.len() != 1 { return Err(invalid()) };
+	match route.iter().next() {
+    	Some(Label::Route(r)) => Ok(Label::Socket(r.clone()).into()),
+    	_ => Err(ServError::new(500, "ws must be followed by a route")),
+	}
+}
+
 pub fn parse_declaration(parser: &mut Parser, ctx: &mut Stack) -> Result<(Option<Address>, ServList), ServError> {
 
     // ignore multiple line breaks in a row
@@ -163,10 +191,33 @@ fn include(module: &mut ServModule, parser: &mut Parser, ctx: &mut Stack) -> Res
     Ok(())
 }
 
+/// `@before /admin/{*rest} => expr` or `@after => expr`, where the route pattern is optional
+fn parse_middleware(parser: &mut Parser, ctx: &mut Stack) -> Result<(Option<String>, ServList), ServError> {
+    let mut lhs = parse_expression(parser, ctx)?;
+    if parser.next_if_kind(Equals).is_err() {
+        return Err(ServError::new(500, "middleware expects a route pattern followed by => and an expression"))
+    }
+
+    let route = match lhs.pop() {
+        Err(_) => None,
+        Ok(ServValue::Ref(addr)) if lhs.len() == 0 => match addr.iter().next() {
+            Some(Label::Route(r)) if addr.len() == 1 => Some(r.clone()),
+            _ => return Err(ServError::new(500, "middleware can only be limited to a route pattern")),
+        },
+        _ => return Err(ServError::new(500, "middleware can only be limited to a route pattern")),
+    };
+
+    Ok((route, parse_expression(parser, ctx)?))
+}
+
 pub fn parse_module(parser: &mut Parser, ctx: &mut Stack) -> Result<ServModule, ServError> {
     let mut output = ServModule::default();
 
 	while parser.get(0).is_ok() {
+    	// blank lines before a directive like `@before` would otherwise be read as part of it
+    	while parser.next_if_kind(ModuleSeparator).is_ok() {}
+    	if parser.get(0).is_err() { break };
+
     	if parser.get(0)?.kind == ModuleClose { break };
     	if parser.get(0)?.kind == Comment { continue };
 
@@ -186,8 +237,28 @@ pub fn parse_module(parser: &mut Parser, ctx: &mut Stack) -> Result<ServModule,
                 	output.insert_declaration(label, value);
 
             	},
+            	"shutdown" => {
+                	parser.incr();
+                	let (label, expr) = parse_declaration(parser, ctx)?;
+                	if label.is_some() {
+                    	return Err(ServError::new(500, "@shutdown expects an expression, not a declaration"));
+                	}
+                	output.shutdown.push(expr);
+            	},
+            	"before" => {
+                	parser.incr();
+                	let middleware = parse_middleware(parser, ctx)?;
+                	output.before.push(middleware);
+            	},
+            	"after" => {
+                	parser.incr();
+                	let middleware = parse_middleware(parser, ctx)?;
+                	output.after.push(middleware);
+            	},
             	otherwise => return Err(ServError::Empty),
         	}
+
+        	continue
     	}
 
     	let (label, value) = parse_declaration(parser, ctx)?;
@@ -96,6 +96,12 @@ fn tokenize_expression(cursor: &mut Tokenizer, output: &mut Vec<Token<TokenKind>
             c if c.is_alphabetic() => {
                 cursor.incr_while(|x| x.is_alphanumeric() || x == '_' || x == '.');
 
+				// `sqlite.query@analytics` picks a named database, see parser::with_database
+				if cursor.get(0) == Some('@') {
+    				cursor.incr(1);
+    				cursor.incr_while(|x| x.is_alphanumeric() || x == '_');
+				}
+
 				// include needs to be special since it is executed at parsetime
                 let mut ident = cursor.emit(TokenKind::Identifier);
                 // if ident.value == "include" { ident.kind = TokenKind::Include };
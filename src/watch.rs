@@ -0,0 +1,113 @@
+use crate::CliArgs;
+use crate::webserver::SharedApp;
+use crate::webserver::livereload;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+
+/// Tracks the modification times of a set of files
+pub struct Watcher(HashMap<PathBuf, Option<SystemTime>>);
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    pub fn add<I: IntoIterator<Item = PathBuf>>(&mut self, paths: I) {
+        for path in paths {
+            let time = modified(&path);
+            self.0.entry(path).or_insert(time);
+        }
+    }
+
+    /// Check whether any of the files have been modified, created, or deleted since the last check
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, last) in self.0.iter_mut() {
+            let now = modified(path);
+            if now != *last {
+                *last = now;
+                changed = true;
+            }
+        }
+
+        changed
+    }
+}
+
+/// The files given on the command line, plus every file that was read while loading them
+fn source_files(args: &CliArgs) -> Vec<PathBuf> {
+    let mut output: Vec<PathBuf> = args.path.iter().map(PathBuf::from).collect();
+    output.extend(crate::functions::take_files_read());
+    output
+}
+
+/// Poll the source files for changes, and swap a freshly loaded app into the running server
+/// whenever they do. If loading fails, the previous version keeps running.
+///
+/// In dev mode, files read while responding to requests are watched as well, and open pages
+/// are told to reload whenever anything changes.
+pub async fn reload_on_change(mut args: CliArgs, app: SharedApp) {
+    let mut sources = Watcher::new();
+    sources.add(source_files(&args));
+
+    let mut served = Watcher::new();
+    if !args.dev { crate::functions::stop_recording_files() };
+
+    let mut interval = tokio::time::interval(Duration::from_millis(500));
+    loop {
+        interval.tick().await;
+
+        if args.dev {
+            served.add(crate::functions::take_files_read());
+            if served.changed() { livereload::notify() };
+        }
+
+        // keep search indexes up to date here, rather than checking the files on every search
+        if let Ok(Err(e)) = tokio::task::spawn_blocking(crate::functions::sync_search_index).await {
+            eprintln!("failed to update the search index: {}", e);
+        }
+
+        if !sources.changed() { continue };
+
+        crate::functions::record_files_read();
+
+        // loading reads files and runs sqlite statements, so it is kept off of the async runtime.
+        // a panic while loading shows up as an error joining the task.
+        let mut loading_args = args.clone();
+        let result = tokio::task::spawn_blocking(move || crate::load(&mut loading_args)).await;
+
+        match result {
+            Ok(Ok(new_app)) => {
+                // includes may have been added or removed, so start watching from scratch
+                sources = Watcher::new();
+                sources.add(source_files(&args));
+
+                let old = std::mem::replace(&mut *app.write().unwrap_or_else(|e| e.into_inner()), Arc::new(new_app));
+                livereload::notify();
+                println!("reloaded");
+
+                // the old app runs its @shutdown statements once the last request using it is done
+                tokio::task::spawn_blocking(move || drop(old));
+            },
+
+            Ok(Err(e)) => {
+                sources.add(source_files(&args));
+                eprintln!("reload failed, still serving the previous version: {}", e);
+            },
+
+            Err(_) => {
+                sources.add(source_files(&args));
+                eprintln!("reload failed, still serving the previous version");
+            },
+        }
+
+        if !args.dev { crate::functions::stop_recording_files() };
+    }
+}
@@ -0,0 +1,135 @@
+use super::ServBody;
+
+use crate::{ServValue, ServError, Stack};
+
+use hyper::{Method, Response, StatusCode};
+use hyper::http::request::Parts;
+use hyper::http::HeaderValue;
+
+const DEFAULT_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE";
+
+/// Which cross origin requests are allowed, read from the `server.cors` table
+#[derive(Clone)]
+pub struct Cors {
+    /// None allows every origin
+    origins: Option<Vec<String>>,
+    methods: String,
+
+    /// None allows whatever headers a preflight request asks for
+    headers: Option<String>,
+    expose: Option<String>,
+    credentials: bool,
+    max_age: Option<i64>,
+}
+
+/// Join a list of values with commas, or use a single value as it is
+fn join(value: &ServValue) -> String {
+    match value {
+        ServValue::List(items) => items.clone().map(|v| v.to_string()).collect::<Vec<_>>().join(", "),
+        value => value.to_string(),
+    }
+}
+
+impl Cors {
+    pub fn from_scope(scope: &Stack) -> Result<Option<Self>, ServError> {
+        let Some(config) = super::get_config_table("server.cors", scope)? else { return Ok(None) };
+
+        let origins = match config.get("origins") {
+            None => None,
+            Some(ServValue::List(items)) => Some(items.clone().map(|v| v.to_string()).collect()),
+            Some(origin) if origin.to_string() == "*" => None,
+            Some(origin) => Some(vec![origin.to_string()]),
+        };
+
+        // echoing back any origin along with credentials would let every site act as the user
+        let credentials = config.get("credentials").is_some_and(|v| v.is_truthy());
+        if credentials && origins.is_none() {
+            return Err(ServError::new(500, "server.cors.credentials needs an explicit list of origins"))
+        }
+
+        let max_age = match config.get("max_age") {
+            Some(value) => Some(value.expect_int()?),
+            None => None,
+        };
+
+        Ok(Some(Self {
+            origins,
+            methods: config.get("methods").map(join).unwrap_or(DEFAULT_METHODS.to_owned()),
+            headers: config.get("headers").map(join),
+            expose: config.get("expose").map(join),
+            credentials,
+            max_age,
+        }))
+    }
+
+    /// The value for `Access-Control-Allow-Origin`, if the origin of a request is allowed
+    fn allow_origin(&self, parts: &Parts) -> Option<HeaderValue> {
+        let origin = parts.headers.get("Origin")?;
+        match self.origins {
+            None => Some(HeaderValue::from_static("*")),
+            Some(ref origins) => origins.iter().any(|o| o.as_bytes() == origin.as_bytes()).then(|| origin.clone()),
+        }
+    }
+
+    /// Whether a request comes from an allowed origin, or from something other than a browser
+    pub fn allows_origin(&self, parts: &Parts) -> bool {
+        !parts.headers.contains_key("Origin") || self.allow_origin(parts).is_some()
+    }
+
+    /// Add the headers that let the browser read a response to a cross origin request
+    pub fn apply(&self, parts: &Parts, response: &mut Response<ServBody>) {
+        let headers = response.headers_mut();
+        if self.origins.is_some() {
+            headers.append("Vary", HeaderValue::from_static("Origin"));
+        }
+
+        let Some(origin) = self.allow_origin(parts) else { return };
+        headers.insert("Access-Control-Allow-Origin", origin);
+
+        if self.credentials {
+            headers.insert("Access-Control-Allow-Credentials", HeaderValue::from_static("true"));
+        }
+
+        if let Some(ref expose) = self.expose {
+            if let Ok(value) = HeaderValue::from_str(expose) {
+                headers.insert("Access-Control-Expose-Headers", value);
+            }
+        }
+    }
+
+    /// Answer a preflight request, without evaluating any routes
+    pub fn preflight(&self, parts: &Parts) -> Response<ServBody> {
+        let mut response = Response::new(ServBody::empty());
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        self.apply(parts, &mut response);
+
+        if self.allow_origin(parts).is_none() { return response };
+        let headers = response.headers_mut();
+
+        if let Ok(methods) = HeaderValue::from_str(&self.methods) {
+            headers.insert("Access-Control-Allow-Methods", methods);
+        }
+
+        let allowed = match self.headers {
+            Some(ref allowed) => HeaderValue::from_str(allowed).ok(),
+            None => parts.headers.get("Access-Control-Request-Headers").cloned(),
+        };
+
+        if let Some(allowed) = allowed {
+            headers.insert("Access-Control-Allow-Headers", allowed);
+        }
+
+        if let Some(seconds) = self.max_age {
+            headers.insert("Access-Control-Max-Age", HeaderValue::from(seconds));
+        }
+
+        response
+    }
+}
+
+/// Whether a request is a browser asking if a cross origin request is allowed
+pub fn is_preflight(parts: &Parts) -> bool {
+    parts.method == Method::OPTIONS
+        && parts.headers.contains_key("Origin")
+        && parts.headers.contains_key("Access-Control-Request-Method")
+}
@@ -0,0 +1,141 @@
+use super::{ServBody, App, eval_route};
+
+use crate::{ServValue, ServError, Stack, Label};
+use crate::engine;
+use crate::functions::Subscription;
+use crate::value::{Serializer, DefaultSerializer};
+
+use hyper::Response;
+use hyper::http::request::Parts;
+use tokio::sync::{broadcast, mpsc, watch};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long an event stream can go without sending anything before it sends a comment,
+/// so that proxies and browsers don't give up on the connection
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Format text as a server sent event, with one `data:` field per line
+pub fn format_event(data: &str) -> String {
+    let mut output = String::new();
+    for line in data.lines() {
+        output.push_str("data: ");
+        output.push_str(line);
+        output.push('\n');
+    }
+
+    output.push('\n');
+    output
+}
+
+/// Wait until the server starts shutting down. The sender is only dropped once the server has
+/// stopped, which shouldn't end a stream on its own.
+pub(super) async fn stopped(stop: &mut watch::Receiver<bool>) {
+    if stop.wait_for(|stop| *stop).await.is_err() {
+        std::future::pending::<()>().await
+    }
+}
+
+/// Forward messages from a broadcast channel to a streaming body as server sent events,
+/// until the client disconnects or the server shuts down
+pub fn forward(initial: Option<String>, mut source: Subscription, mut stop: watch::Receiver<bool>) -> ServBody {
+    let (sender, receiver) = mpsc::channel(16);
+
+    tokio::task::spawn(async move {
+        if let Some(data) = initial {
+            if sender.send(format_event(&data).into_bytes().into()).await.is_err() { return };
+        }
+
+        let mut keep_alive = tokio::time::interval(KEEP_ALIVE);
+        loop {
+            let chunk = tokio::select! {
+                message = source.recv() => match message {
+                    Ok(data) => format_event(&data),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = keep_alive.tick() => ": keep-alive\n\n".to_owned(),
+                _ = stopped(&mut stop) => break,
+            };
+
+            if sender.send(chunk.into_bytes().into()).await.is_err() { break };
+        }
+    });
+
+    ServBody::Stream(receiver)
+}
+
+/// Wrap a streaming body in a response with the headers that `EventSource` expects
+pub fn response(body: ServBody) -> Response<ServBody> {
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert("Content-Type", "text/event-stream".parse().unwrap());
+    headers.insert("Cache-Control", "no-cache".parse().unwrap());
+    response
+}
+
+fn serialize(value: ServValue, scope: &Stack) -> Result<String, ServError> {
+    let mut output = String::new();
+    DefaultSerializer(scope).write(value, &mut output)?;
+    Ok(output)
+}
+
+/// Evaluate an expression in a copy of the scope of the request that opened the stream
+fn evaluate(app: &App, words: &HashMap<Label, ServValue>, request: &Option<Parts>, expr: &ServValue) -> Result<String, ServError> {
+    let mut scope = app.scope.make_child();
+    scope.words = words.clone();
+    scope.request = request.clone();
+
+    let value = eval_route(expr.clone(), &mut scope)?;
+    serialize(value, &scope)
+}
+
+/// Re-evaluate an expression on an interval, sending each result as an event
+fn every(period: Duration, expr: ServValue, app: Arc<App>, scope: &Stack, stop: watch::Receiver<bool>) -> ServBody {
+    let words = Arc::new(scope.words.clone());
+    let request = Arc::new(scope.request.clone());
+    let expr = Arc::new(expr);
+    let (sender, receiver) = broadcast::channel(4);
+
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let (app, words, request, expr) = (app.clone(), words.clone(), request.clone(), expr.clone());
+            let result = tokio::task::spawn_blocking(move || evaluate(&app, &words, &request, &expr)).await;
+            let data = match result {
+                Ok(Ok(data)) => data,
+                Ok(Err(e)) => { eprintln!("error in event stream: {}", e); continue },
+                Err(_) => { eprintln!("panic in event stream"); continue },
+            };
+
+            // fails once the stream has been closed and dropped its receiver
+            if sender.send(data).is_err() { break };
+        }
+    });
+
+    forward(None, receiver.into(), stop)
+}
+
+/// Build an event stream response from the `res.sse` table that the `sse`
+/// functions leave in the scope of a request
+pub fn from_config(config: ServValue, value: ServValue, app: &Arc<App>, scope: &Stack, stop: watch::Receiver<bool>) -> Result<Response<ServBody>, ServError> {
+    let config = config.expect_module()?;
+
+    if let Some(name) = config.values.get(&"channel".into()) {
+        let source = crate::functions::subscribe(&name.to_string());
+        let initial = match value {
+            ServValue::None => None,
+            value => Some(serialize(value, scope)?),
+        };
+
+        return Ok(response(forward(initial, source, stop)))
+    }
+
+    let seconds = config.values.get(&"every".into()).ok_or(ServError::new(500, "invalid event stream"))?.expect_int()?;
+    let expr = config.values.get(&"expr".into()).cloned().unwrap_or_default();
+    let period = Duration::from_secs(seconds.try_into().map_err(|_| "invalid interval")?);
+
+    Ok(response(every(period, expr, app.clone(), scope, stop)))
+}
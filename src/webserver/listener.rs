@@ -0,0 +1,100 @@
+use crate::{ServValue, ServError, Stack};
+use crate::engine;
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+
+/// An address that the webserver accepts connections on, taken from
+/// `server.listen`, or from `server.host` and `server.port` if that is not defined.
+///
+/// Entries in `server.listen` can take any of these forms:
+///
+/// ```text
+/// 0.0.0.0:4000          # encrypted if a certificate is configured
+/// http://[::]:80        # always plain http
+/// https://[::]:443      # always encrypted
+/// unix:/run/serv.sock   # unix domain socket, for running behind a proxy
+/// ```
+#[derive(Debug, Clone)]
+pub enum Listen {
+    Tcp { addr: SocketAddr, tls: bool },
+    Unix(PathBuf),
+}
+
+impl Listen {
+    fn parse(text: &str, default_port: u16, has_tls: bool) -> Result<Self, ServError> {
+        let text = text.trim();
+
+        if let Some(path) = text.strip_prefix("unix:") {
+            return Ok(Self::Unix(path.into()))
+        }
+
+        if let Some(addr) = text.strip_prefix("https://") {
+            if !has_tls {
+                return Err(ServError::new(500, &format!("{} requires server.certificate and server.private_key", text)))
+            }
+            return Ok(Self::Tcp { addr: resolve_addr(addr, default_port)?, tls: true })
+        }
+
+        if let Some(addr) = text.strip_prefix("http://") {
+            return Ok(Self::Tcp { addr: resolve_addr(addr, default_port)?, tls: false })
+        }
+
+        Ok(Self::Tcp { addr: resolve_addr(text, default_port)?, tls: has_tls })
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Self::Tcp { tls: true, .. })
+    }
+}
+
+impl std::fmt::Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Tcp { addr, tls: true }  => write!(f, "https://{}", addr),
+            Self::Tcp { addr, tls: false } => write!(f, "http://{}", addr),
+            Self::Unix(path)               => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Resolve text like `0.0.0.0:80`, `[::1]:443`, `localhost` or `::` into a socket address,
+/// falling back on the default port if the text does not include one
+fn resolve_addr(text: &str, default_port: u16) -> Result<SocketAddr, ServError> {
+    let invalid = || ServError::new(500, &format!("invalid listen address: {}", text));
+
+    let mut addrs = text.to_socket_addrs()
+        .or_else(|_| (text.trim_start_matches('[').trim_end_matches(']'), default_port).to_socket_addrs())
+        .map_err(|_| invalid())?;
+
+    addrs.next().ok_or_else(invalid)
+}
+
+fn get_host(scope: &Stack) -> Result<String, ServError> {
+    match engine::resolve_key("server.host", scope) {
+        Ok(value) => Ok(value.to_string()),
+        Err(_)    => Ok("0.0.0.0".to_owned()),
+    }
+}
+
+/// Read the list of addresses to listen on from the server config
+pub fn get_listeners(scope: &Stack, port: u16, has_tls: bool) -> Result<Vec<Listen>, ServError> {
+    let Ok(value) = engine::resolve_key("server.listen", scope) else {
+        let host = get_host(scope)?;
+        return Ok(vec![Listen::Tcp { addr: resolve_addr(&host, port)?, tls: has_tls }])
+    };
+
+    let entries: Vec<ServValue> = match value {
+        ServValue::List(list) => list.collect(),
+        ServValue::None       => Vec::new(),
+        single                => vec![single],
+    };
+
+    if entries.is_empty() {
+        return Err(ServError::new(500, "server.listen does not contain any addresses"))
+    }
+
+    entries.iter()
+        .map(|entry| Listen::parse(entry.as_str()?, port, has_tls))
+        .collect()
+}
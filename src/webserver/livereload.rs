@@ -0,0 +1,61 @@
+use super::{ServBody, events};
+
+use hyper::Response;
+use tokio::sync::{broadcast, watch};
+use std::sync::OnceLock;
+
+/// The path that pages served in dev mode listen on for changes
+pub const ENDPOINT: &str = "/_serv/livereload";
+
+const SCRIPT: &str = "<script>new EventSource(\"/_serv/livereload\").onmessage = () => location.reload();</script>";
+
+static RELOADS: OnceLock<broadcast::Sender<String>> = OnceLock::new();
+
+/// Turn on dev mode, where html responses reload themselves when a file changes
+pub fn enable() {
+    RELOADS.get_or_init(|| broadcast::channel(16).0);
+}
+
+pub fn is_enabled() -> bool {
+    RELOADS.get().is_some()
+}
+
+/// Tell every open page to reload
+pub fn notify() {
+    if let Some(sender) = RELOADS.get() {
+        _ = sender.send("reload".to_owned());
+    }
+}
+
+pub fn stream(stop: watch::Receiver<bool>) -> Response<ServBody> {
+    let Some(sender) = RELOADS.get() else {
+        return super::not_found()
+    };
+
+    events::response(events::forward(None, sender.subscribe().into(), stop))
+}
+
+fn find_last(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|window| window.eq_ignore_ascii_case(needle))
+}
+
+/// Add the reload script to an html response, just before the closing body tag if there is one.
+/// Responses without a content type are only changed if they look like an html page.
+pub fn inject_script(response: &mut Response<ServBody>) {
+    let content_type = response.headers().get("Content-Type").and_then(|v| v.to_str().ok()).map(|v| v.to_owned());
+
+    let ServBody::Full(Some(data)) = response.body_mut() else { return };
+    let text = data.make_contiguous();
+
+    let is_html = match content_type {
+        Some(mime) => mime.starts_with("text/html"),
+        None => find_last(text, b"</body>").is_some() || find_last(text, b"<html").is_some(),
+    };
+
+    if !is_html { return };
+
+    let position = find_last(text, b"</body>").unwrap_or(text.len());
+    let tail: Vec<u8> = data.drain(position..).collect();
+    data.extend(SCRIPT.bytes());
+    data.extend(tail);
+}
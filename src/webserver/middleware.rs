@@ -0,0 +1,66 @@
+use super::routes::Routes;
+
+use crate::{ServValue, ServError, Stack};
+use crate::value::ServList;
+
+/// An expression that runs before or after every route matching its pattern
+pub struct Middleware {
+    routes: Option<Routes>,
+    expr: ServList,
+}
+
+impl Middleware {
+    pub fn new(route: &Option<String>, expr: &ServList) -> Result<Self, ServError> {
+        let routes = match route {
+            Some(pattern) => {
+                let mut routes = Routes::new();
+                routes.insert(pattern, ServValue::None)?;
+                Some(routes)
+            },
+            None => None,
+        };
+
+        Ok(Self { routes, expr: expr.clone() })
+    }
+
+    /// Whether a path matches, binding any variables in the pattern into scope if it does
+    fn matches(&self, path: &str, scope: &mut Stack) -> Result<bool, ServError> {
+        let Some(ref routes) = self.routes else { return Ok(true) };
+        let Some((_, params, _)) = routes.at(path) else { return Ok(false) };
+
+        for (k, v) in params.into_iter() {
+            scope.insert(k.as_str(), v)?;
+        }
+
+        Ok(true)
+    }
+}
+
+/// Run `@before` middleware in the scope of a request, so that anything it adds to the scope is
+/// seen by the route. Middleware that returns a value answers the request with it, and the route
+/// isn't evaluated.
+pub fn before(middleware: &[Middleware], path: &str, scope: &mut Stack) -> Result<Option<ServValue>, ServError> {
+    for m in middleware.iter() {
+        if !m.matches(path, scope)? { continue };
+
+        match m.expr.clone().eval(scope)? {
+            ServValue::None => continue,
+            value => return Ok(Some(value)),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Run `@after` middleware with the result of a route as its input, and respond with what it returns
+pub fn after(middleware: &[Middleware], path: &str, mut value: ServValue, scope: &mut Stack) -> Result<ServValue, ServError> {
+    for m in middleware.iter() {
+        if !m.matches(path, scope)? { continue };
+
+        let mut expr = m.expr.clone();
+        expr.push_back(value);
+        value = expr.eval(scope)?;
+    }
+
+    Ok(value)
+}
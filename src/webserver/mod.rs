@@ -0,0 +1,1163 @@
+use hyper::service::Service;
+use hyper::body::{Body, Frame, Incoming as IncomingBody};
+use hyper::{ Request, Response, StatusCode };
+use hyper::http::request::Parts;
+use std::sync::{Arc, Mutex, RwLock};
+use std::pin::Pin;
+use std::future::Future;
+use std::task::{Poll, Context};
+use tokio_rustls::rustls;
+
+use crate::{ServFn, ServModule};
+use crate::ServError;
+
+use crate::engine;
+use crate::functions::MATCHED_ROUTE;
+
+
+use hyper_util::rt::TokioIo;
+
+use hyper::server::conn::http1;
+
+use std::io::BufReader;
+
+use crate::{ServValue, Label};
+use crate::Stack;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::sync::{watch, mpsc};
+
+use crate::ServList;
+
+mod listener;
+mod events;
+mod websocket;
+mod stream;
+mod routes;
+mod middleware;
+mod cors;
+mod ratelimit;
+pub mod livereload;
+
+use listener::Listen;
+use routes::Routes;
+use middleware::Middleware;
+use cors::Cors;
+use ratelimit::RateLimit;
+
+pub enum ServBody {
+    Full(Option<VecDeque<u8>>),
+
+    /// A body that is sent in chunks as they arrive, until the sender is dropped
+    Stream(mpsc::Receiver<VecDeque<u8>>),
+}
+
+impl ServBody {
+    pub fn empty() -> Self {
+        Self::Full(None)
+    }
+
+    pub fn from_text(input: &str) -> Self {
+        Self::Full(Some(input.bytes().collect()))
+    }
+
+    pub fn generate(input: ServValue, scope: &Stack) -> Result<Self, ServError> {
+        match input {
+			ServValue::Ref(ref addr) => ServBody::generate(crate::engine::deref(addr, scope)?, scope),
+			ServValue::Func(_) => ServBody::generate(crate::engine::resolve(input, None, scope)?, scope),
+
+			ServValue::Text(s) if !s.is_str() => Ok(Self::Full(Some(s.as_bytes().into_iter().map(|x| x.clone()).collect()))),
+			otherwise => {
+    			let mut output = String::new();
+				crate::value::DefaultSerializer(scope).write(otherwise, &mut output)?;
+				Ok(Self::from_text(&output))
+			},
+       }
+    }
+}
+
+impl Body for ServBody {
+	type Data = VecDeque<u8>;
+	type Error = &'static str;
+
+	fn poll_frame(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+    	match self.get_mut() {
+        	Self::Full(data) => Poll::Ready(data.take().map(|d| Ok(Frame::data(d)))),
+        	Self::Stream(receiver) => receiver.poll_recv(cx).map(|chunk| chunk.map(|d| Ok(Frame::data(d)))),
+    	}
+	}
+}
+
+use http_body_util::BodyExt;
+use crate::value::Serializer;
+
+fn get_mime_type<'a>(value: &'a ServValue, scope: &'a Stack) -> Option<String> {
+    if let Ok(v) = engine::deref(&"res.mime".into(), scope) {
+		return Some(v.call(None, scope).ok()?.to_string());
+    }
+
+    if let ServValue::Text(t) = value {
+        return Some(t.mime?.to_string())
+    }
+
+    None
+}
+
+fn response_from_value(input: ServValue, scope: &mut Stack) -> Result<Response<ServBody>, ServError> {
+    let mut response = Response::builder();
+	response = response.status(200);
+
+	if let Some(mime) = get_mime_type(&input, &scope) {
+    	response = response.header("Content-Type", mime);
+	}
+
+	match crate::engine::deref(&"res.headers".into(), scope) {
+    	Ok(ServValue::Module(m)) => for (p, a) in m.values {
+        	let key   = p.to_string();
+        	let value = a.call(None, scope)?.to_string();
+        	response = response.header(&key, &value);
+    	},
+    	Ok(ServValue::Table(t)) => for (key, value) in t {
+        	response = response.header(&key, &value.to_string());
+    	},
+    	_ => {},
+	}
+
+	if let Ok(ServValue::Module(m)) = engine::deref(&"res.cookie".into(), scope) {
+    	for (p, a) in m.values {
+        	let key   = p.to_string();
+        	let value = engine::resolve(a, None, scope)?;
+        	let cookie_text = format!("{}={};path=/;SameSite=Strict", key, value );
+        	response = response.header("Set-Cookie", &cookie_text);
+    	}
+	}
+
+	// invalid header names or values only show up as an error here
+	Ok(response.body(ServBody::generate(input, scope)?)?)
+}
+
+fn response_from_error(input: ServError) -> Response<ServBody> {
+    let mut response = Response::new(ServBody::from_text(&input.to_string()));
+    *response.status_mut() = StatusCode::from_u16(input.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+    match input {
+        ServError::RateLimited(seconds) => {
+            response.headers_mut().insert("Retry-After", seconds.into());
+        },
+        ServError::Unauthorized(challenge) => if let Ok(value) = challenge.parse() {
+            response.headers_mut().insert("WWW-Authenticate", value);
+        },
+        _ => {},
+    }
+
+    response
+}
+
+fn not_found() -> Response<ServBody> {
+    let mut response = Response::new(ServBody::from_text("<h1>Error 404: Page Not Found</h1>"));
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+fn eval_route(value: ServValue, scope: &mut Stack) -> Result<ServValue, ServError> {
+    match value {
+        ServValue::Func(ServFn::Expr(e, _)) => e.clone().eval(scope),
+        value => value.call(None, scope),
+    }
+}
+
+/// Read a table of options from the server module, which can be assigned either as a table
+/// or field by field, ie. `server.cors.origins = ...`
+fn get_config_table(key: &str, scope: &Stack) -> Result<Option<HashMap<String, ServValue>>, ServError> {
+    let Ok(config) = engine::resolve_key(key, scope) else { return Ok(None) };
+
+    match config {
+        ServValue::Table(t) => Ok(Some(t)),
+        ServValue::Module(m) => Ok(Some(m.values.into_iter()
+            .map(|(k, v)| Ok((k.to_string(), v.call(None, scope)?)))
+            .collect::<Result<_, ServError>>()?)),
+        ServValue::Bool(false) | ServValue::None => Ok(None),
+        _ => Err(ServError::new(500, &format!("{} should be a table, ie. {}.name = ...", key, key))),
+    }
+}
+
+/// Run `@before` middleware for a websocket route before accepting the connection, returning the
+/// words its handler is evaluated with, or the response that middleware answered with instead
+fn before_upgrade(app: &App, params: Vec<(String, ServValue)>, pattern: String, request: Parts) -> Result<HashMap<Label, ServValue>, Box<Response<ServBody>>> {
+    let mut scope = app.scope.make_child();
+    let path = request.uri.path().to_owned();
+    scope.request = Some(request);
+
+    let answer = scope.insert(Label::Name(MATCHED_ROUTE.to_owned()), ServValue::from(pattern))
+        .and_then(|_| params.into_iter().try_for_each(|(k, v)| scope.insert(k.as_str(), v)))
+        .and_then(|_| middleware::before(&app.before, &path, &mut scope));
+
+    match answer {
+        Ok(None) => Ok(scope.words),
+        Ok(Some(value)) => Err(Box::new(response_from_value(value, &mut scope).unwrap_or_else(response_from_error))),
+        Err(error) => Err(Box::new(response_from_error(error))),
+    }
+}
+
+/// Everything needed to respond to requests, built from the root module
+pub struct App {
+    pub scope: Stack<'static>,
+    pub router: Routes,
+    pub sockets: Routes,
+    /// `@shutdown` statements, which are taken when they are run so they only ever run once
+    pub shutdown: Mutex<Vec<ServList>>,
+    pub before: Vec<Middleware>,
+    pub after: Vec<Middleware>,
+    pub cors: Option<Cors>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl App {
+    /// Build the app, failing if any of the routes conflict with each other,
+    /// or if `server.cors` or `server.rate_limit` are invalid
+    pub fn new(scope: Stack<'static>, root: &ServModule) -> Result<Self, ServError> {
+        let mut router = Routes::new();
+        for (route, value) in root.routes() {
+            router.insert(route, value.clone())?;
+        }
+
+        let mut sockets = Routes::new();
+        for (route, value) in root.sockets() {
+            sockets.insert(route, value.clone())?;
+        }
+
+        let before = root.before.iter().map(|(route, expr)| Middleware::new(route, expr)).collect::<Result<_, _>>()?;
+        let after = root.after.iter().map(|(route, expr)| Middleware::new(route, expr)).collect::<Result<_, _>>()?;
+
+        let cors = Cors::from_scope(&scope)?;
+        let rate_limit = RateLimit::from_scope(&scope)?;
+
+        Ok(Self { scope, router, sockets, shutdown: Mutex::new(root.shutdown.clone()), before, after, cors, rate_limit })
+    }
+}
+
+impl App {
+    /// Run the `@shutdown` statements of the root module, if they haven't been run already
+    pub fn run_shutdown_statements(&self) {
+        let statements = std::mem::take(&mut *self.shutdown.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut child = self.scope.make_child();
+        for expr in statements {
+            if let Err(e) = engine::eval(expr, &mut child) {
+                eprintln!("error during shutdown: {}", e);
+            }
+        }
+    }
+}
+
+/// An app that is replaced while the server is running shuts down once nothing is using it
+impl Drop for App {
+    fn drop(&mut self) {
+        self.run_shutdown_statements();
+    }
+}
+
+/// The app currently being served, which can be replaced while the server is running
+pub type SharedApp = Arc<RwLock<Arc<App>>>;
+
+fn current(app: &SharedApp) -> Arc<App> {
+    app.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// The service for a single connection, with a receiver that flips to true when the server starts
+/// shutting down, so that long running responses know when to end, and the address of the client
+/// if it connected over tcp
+#[derive(Clone)]
+struct Serv(SharedApp, watch::Receiver<bool>, Option<SocketAddr>);
+
+impl Service<Request<IncomingBody>> for Serv {
+	type Response = Response<ServBody>;
+	type Error = hyper::Error;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+	fn call(&self, mut req: Request<IncomingBody>) -> Self::Future {
+    	let app = current(&self.0);
+    	let stop = self.1.clone();
+    	if let Some(addr) = self.2 { req.extensions_mut().insert(addr); }
+
+    	let output = async move {
+        	let path = req.uri().path().to_owned();
+        	let socket = app.sockets.at(&path);
+
+        	let (parts, body) = req.into_parts();
+        	let parts_a = parts.clone();
+
+        	if livereload::is_enabled() && parts_a.uri.path() == livereload::ENDPOINT {
+            	return Ok(livereload::stream(stop))
+        	}
+
+        	let cors = app.cors.clone();
+        	if let Some(ref cors) = cors {
+            	if cors::is_preflight(&parts_a) { return Ok(cors.preflight(&parts_a)) };
+        	}
+
+        	let with_cors = |mut response: Response<ServBody>| {
+            	if let Some(ref cors) = cors { cors.apply(&parts_a, &mut response) };
+            	response
+        	};
+
+        	if let Some(Err(error)) = app.rate_limit.as_ref().map(|limit| limit.check(&parts_a)) {
+            	return Ok(with_cors(response_from_error(error)))
+        	}
+
+        	if let (Some((handler, params, pattern)), true) = (socket.as_ref(), websocket::is_upgrade(&parts_a)) {
+            	// browsers don't send a preflight request before opening a websocket, so a
+            	// connection from a page on another site has to be refused here instead
+            	if cors.as_ref().is_some_and(|cors| !cors.allows_origin(&parts_a)) {
+                	return Ok(response_from_error(ServError::new(403, "origin not allowed")))
+            	}
+
+            	let (checking, params, pattern, request) = (app.clone(), params.clone(), pattern.to_string(), parts_a.clone());
+            	let words = match tokio::task::spawn_blocking(move || before_upgrade(&checking, params, pattern, request)).await {
+                	Ok(Ok(words)) => words,
+                	Ok(Err(response)) => return Ok(with_cors(*response)),
+                	Err(_) => return Ok(response_from_error(ServError::new(500, "internal server error"))),
+            	};
+
+            	return Ok(websocket::upgrade(Request::from_parts(parts, body), handler.clone(), words, app.clone(), stop))
+        	}
+
+        	let Some((handler, params, pattern)) = app.router.at(parts_a.uri.path()) else {
+            	if socket.is_some() { return Ok(websocket::upgrade_required()) };
+            	return Ok(with_cors(not_found()))
+        	};
+
+        	let pattern = pattern.to_owned();
+
+        	let body: bytes::Bytes = match body.collect().await {
+            	Ok(collected) => collected.to_bytes(),
+            	Err(e) => {
+                	eprintln!("{} {}: could not read request body: {}", parts_a.method, parts_a.uri.path(), e);
+                	return Ok(response_from_error(ServError::new(400, "could not read request body")))
+            	},
+        	};
+
+			// routes can block on sqlite or the filesystem, so they are evaluated off of the async runtime
+        	let evaluation = tokio::task::spawn_blocking(move || {
+        		let mut scope = app.scope.make_child();
+            	scope.insert(Label::Name(MATCHED_ROUTE.to_owned()), ServValue::from(pattern))?;
+            	for (k, v) in params.into_iter() {
+        			scope.insert(k.as_str(), v)?;
+            	}
+
+            	// `req` also holds functions like `req.query`, which a new module for the body would hide
+            	let mut request = match scope.get("req") {
+                	Ok(ServValue::Module(m)) => m,
+                	_ => ServModule::empty(),
+            	};
+            	request.insert("body", ServValue::Text(body.into()))?;
+            	scope.insert("req", request.into())?;
+            	let path = parts.uri.path().to_owned();
+            	scope.request = Some(parts);
+
+            	if let Some(value) = middleware::before(&app.before, &path, &mut scope)? {
+                	return response_from_value(value, &mut scope)
+            	}
+
+            	let value = eval_route(handler, &mut scope)?;
+            	let value = middleware::after(&app.after, &path, value, &mut scope)?;
+            	if let Ok(config) = engine::deref(&"res.sse".into(), &scope) {
+                	return events::from_config(config, value, &app, &scope, stop)
+            	}
+
+            	if let Ok(config) = engine::deref(&"res.stream".into(), &scope) {
+                	return stream::from_config(config, &app, &scope)
+            	}
+
+            	response_from_value(value, &mut scope)
+        	});
+
+        	let mut response = match evaluation.await {
+            	Ok(Ok(response)) => response,
+            	Ok(Err(error)) => {
+                	eprintln!("{} {}: {}", parts_a.method, parts_a.uri.path(), error);
+                	response_from_error(error)
+            	},
+
+				// a panic in one of the script functions only fails this request
+            	Err(_) => {
+                	eprintln!("{} {}: panicked while evaluating route", parts_a.method, parts_a.uri.path());
+                	response_from_error(ServError::new(500, "internal server error"))
+            	},
+        	};
+
+        	if livereload::is_enabled() {
+            	livereload::inject_script(&mut response);
+        	}
+
+        	Ok(with_cors(response))
+    	};
+
+    	Box::pin(output)
+	}
+}
+
+fn get_port(scope: &Stack) -> Result<u16, ServError> {
+    match engine::resolve_key("server.port", scope) {
+        Ok(val) => {
+            Ok(val.call(None, &scope)?.expect_int()?.try_into().map_err(|_| "invalid port")?)
+        },
+        Err(_) => Ok(4000),
+    }
+}
+
+fn get_tls_info(scope: &Stack<'static>) -> Option<Arc<rustls::ServerConfig>> {
+    let key = engine::resolve_key("server.private_key", scope).ok()?.to_string();
+
+    let mut reader = BufReader::new(key.as_bytes());
+    let key = rustls_pemfile::private_key(&mut reader)
+        .expect("failed to parse private key")
+        .expect("failed to find private key");
+
+    let cert = engine::resolve_key("server.certificate", scope).ok()?.to_string();
+
+    let mut reader_cert = BufReader::new(cert.as_bytes());
+    let certs = rustls_pemfile::certs(&mut reader_cert)
+        .map(|cert| cert.expect("failed to parse cert"))
+    	.collect();
+
+	let output = rustls::ServerConfig::builder()
+    	.with_no_client_auth()
+    	.with_single_cert(certs, key)
+    	.expect("failed to build config");
+
+	Some(Arc::new(output))
+}
+
+
+fn get_https_redirect(scope: &Stack) -> bool {
+    engine::resolve_key("server.https_redirect", scope)
+        .map(|v| v.is_truthy())
+        .unwrap_or(false)
+}
+
+/// Answer every request with a permanent redirect to the same path over https
+fn redirect_to_https(req: Request<IncomingBody>, port: u16) -> Response<ServBody> {
+    let host = req.headers().get("Host")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<hyper::http::uri::Authority>().ok())
+        .map(|authority| authority.host().to_owned())
+        .unwrap_or("localhost".to_owned());
+
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = match port {
+        443  => format!("https://{}{}", host, path),
+        port => format!("https://{}:{}{}", host, port, path),
+    };
+
+	Response::builder()
+    	.status(308)
+    	.header("Location", location)
+    	.body(ServBody::empty())
+    	.unwrap_or_else(|_| response_from_error(ServError::new(400, "invalid host")))
+}
+
+/// Handed to every listener and connection so that they can be told to stop,
+/// and so that the server can tell when all of them have finished
+#[derive(Clone)]
+struct Shutdown {
+    signal: watch::Receiver<bool>,
+    _drain: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    async fn wait(&mut self) {
+        _ = self.signal.wait_for(|stop| *stop).await;
+    }
+}
+
+/// Resolves on the first SIGINT (ctrl-c) or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => { s.recv().await; },
+            Err(_)    => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c    => {},
+        _ = terminate => {},
+    }
+}
+
+fn get_shutdown_timeout(scope: &Stack) -> Duration {
+    let seconds = engine::resolve_key("server.shutdown_timeout", scope)
+        .and_then(|v| Ok(v.expect_int()?))
+        .unwrap_or(10);
+
+    Duration::from_secs(seconds.try_into().unwrap_or(0))
+}
+
+/// Serve a connection until it closes, or until the server shuts down, in which case
+/// the request currently in flight is allowed to finish before the connection closes
+async fn serve_connection<I, S>(io: I, service: S, mut shutdown: Shutdown)
+where
+	I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+	S: hyper::service::HttpService<IncomingBody, ResBody = ServBody>,
+	S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+	let connection = http1::Builder::new().serve_connection(TokioIo::new(io), service).with_upgrades();
+	tokio::pin!(connection);
+
+	let mut stopping = false;
+	loop {
+    	tokio::select! {
+        	result = connection.as_mut() => {
+            	if let Err(e) = result { eprintln!("connection error: {}", e) };
+            	break
+        	},
+        	_ = shutdown.wait(), if !stopping => {
+            	stopping = true;
+            	connection.as_mut().graceful_shutdown();
+        	},
+    	}
+	}
+}
+
+async fn serve_redirect<I>(io: I, port: u16, shutdown: Shutdown)
+where I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static {
+    let service = hyper::service::service_fn(move |req| async move {
+        Ok::<_, std::convert::Infallible>(redirect_to_https(req, port))
+    });
+
+	serve_connection(io, service, shutdown).await;
+}
+
+/// Wait for the next connection, or return None once the server starts shutting down
+async fn next_connection<T, F>(mut accept: impl FnMut() -> F, shutdown: &mut Shutdown) -> Option<T>
+where F: Future<Output = std::io::Result<T>> {
+    loop {
+        tokio::select! {
+            result = accept() => if let Ok(connection) = result { return Some(connection) },
+            _ = shutdown.wait() => return None,
+        }
+    }
+}
+
+enum Bound {
+    Tcp(TcpListener, Option<tokio_rustls::TlsAcceptor>, Option<u16>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+async fn bind(listen: &Listen, tls: &Option<Arc<rustls::ServerConfig>>, redirect: Option<u16>) -> Result<Bound, ServError> {
+    match listen {
+        Listen::Tcp { addr, tls: is_tls } => {
+            let listener = TcpListener::bind(addr).await?;
+            let acceptor = match (is_tls, tls) {
+                (true, Some(config)) => Some(tokio_rustls::TlsAcceptor::from(config.clone())),
+                _ => None,
+            };
+
+            let redirect = if acceptor.is_none() { redirect } else { None };
+            Ok(Bound::Tcp(listener, acceptor, redirect))
+        },
+
+        #[cfg(unix)]
+        Listen::Unix(path) => {
+            // a socket file left behind by a previous run would make bind fail
+            use std::os::unix::fs::FileTypeExt;
+            if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+
+            Ok(Bound::Unix(tokio::net::UnixListener::bind(path)?))
+        },
+
+        #[cfg(not(unix))]
+        Listen::Unix(_) => Err(ServError::new(500, "unix sockets are not supported on this platform")),
+    }
+}
+
+async fn accept_loop(bound: Bound, app: SharedApp, mut shutdown: Shutdown) {
+    match bound {
+        Bound::Tcp(listener, Some(tls_acceptor), _) => {
+            while let Some((tcp_stream, addr)) = next_connection(|| listener.accept(), &mut shutdown).await {
+        		let serv_context = Serv(app.clone(), shutdown.signal.clone(), Some(addr));
+        		let tls_acceptor = tls_acceptor.clone();
+        		let shutdown = shutdown.clone();
+
+        		tokio::task::spawn(async move {
+            		let tls_stream = match tls_acceptor.accept(tcp_stream).await {
+                		Ok(stream) => stream,
+                		Err(e) => return eprintln!("tls handshake failed: {}", e),
+            		};
+
+            		serve_connection(tls_stream, serv_context, shutdown).await;
+        		});
+            }
+        },
+
+        Bound::Tcp(listener, None, Some(port)) => {
+            while let Some((tcp_stream, _)) = next_connection(|| listener.accept(), &mut shutdown).await {
+        		tokio::task::spawn(serve_redirect(tcp_stream, port, shutdown.clone()));
+            }
+        },
+
+        Bound::Tcp(listener, None, None) => {
+            while let Some((tcp_stream, addr)) = next_connection(|| listener.accept(), &mut shutdown).await {
+        		let serv_context = Serv(app.clone(), shutdown.signal.clone(), Some(addr));
+        		tokio::task::spawn(serve_connection(tcp_stream, serv_context, shutdown.clone()));
+            }
+        },
+
+        #[cfg(unix)]
+        Bound::Unix(listener) => {
+            while let Some((unix_stream, _)) = next_connection(|| listener.accept(), &mut shutdown).await {
+        		let serv_context = Serv(app.clone(), shutdown.signal.clone(), None);
+        		tokio::task::spawn(serve_connection(unix_stream, serv_context, shutdown.clone()));
+            }
+        },
+    }
+}
+
+
+pub async fn run_webserver(app: SharedApp) -> Result<(), ServError> {
+    // listeners are only read on startup, so changing them requires a restart
+    let initial = current(&app);
+    let port: u16 = get_port(&initial.scope).unwrap_or(4000);
+	let tls = get_tls_info(&initial.scope);
+	let listeners = listener::get_listeners(&initial.scope, port, tls.is_some())?;
+
+	let redirect = match get_https_redirect(&initial.scope) {
+    	true  => listeners.iter().find_map(|l| match l {
+        	Listen::Tcp { addr, tls: true } => Some(addr.port()),
+        	_ => None,
+    	}),
+    	false => None,
+	};
+
+	let mut bound = Vec::new();
+	for listen in listeners.iter() {
+    	bound.push(bind(listen, &tls, redirect).await?);
+    	println!("listening on {}", listen);
+	}
+
+	let (stop, signal) = watch::channel(false);
+	let (drain, mut drained) = mpsc::channel::<()>(1);
+	let shutdown = Shutdown { signal, _drain: drain };
+
+	for b in bound {
+    	tokio::task::spawn(accept_loop(b, app.clone(), shutdown.clone()));
+	}
+
+	drop(shutdown);
+	shutdown_signal().await;
+
+	println!("shutting down, waiting for open connections to finish");
+	_ = stop.send(true);
+
+	// every listener and connection holds a sender, so recv returns once they have all closed
+	let timeout = get_shutdown_timeout(&initial.scope);
+	if tokio::time::timeout(timeout, drained.recv()).await.is_err() {
+    	eprintln!("timed out after {:?}, closing remaining connections", timeout);
+	}
+
+	current(&app).run_shutdown_statements();
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn app(source: &str) -> SharedApp {
+        let mut scope = Stack::empty();
+        scope.insert_module(crate::functions::standard_library().values);
+        let module = crate::parser::parse_root_from_text(source, &mut scope).unwrap();
+        scope.insert_module(module.values.clone());
+
+        for expr in &module.statements {
+            crate::engine::eval(expr.clone(), &mut scope).unwrap();
+        }
+
+        Arc::new(RwLock::new(Arc::new(App::new(scope, &module).unwrap())))
+    }
+
+    /// Write raw bytes to a server over an in memory connection and return everything it
+    /// sends back before closing, asserting that the connection task did not panic
+    async fn send(source: &str, request: &[u8], close_write: bool) -> String {
+        let (_stop, signal) = watch::channel(false);
+        let (drain, _drained) = mpsc::channel(1);
+        let serv_context = Serv(app(source), signal.clone(), None);
+        let shutdown = Shutdown { signal, _drain: drain };
+
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let task = tokio::spawn(serve_connection(server, serv_context, shutdown));
+
+        client.write_all(request).await.unwrap();
+        if close_write { client.shutdown().await.unwrap() };
+
+        let mut response = Vec::new();
+        let read = client.read_to_end(&mut response);
+        tokio::time::timeout(Duration::from_secs(5), read).await.unwrap().unwrap();
+
+        assert!(task.await.is_ok(), "connection task panicked");
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    /// Open a websocket to an app over an in memory connection
+    async fn connect(app: SharedApp, path: &str) -> tokio_tungstenite::WebSocketStream<tokio::io::DuplexStream> {
+        let (stop, signal) = watch::channel(false);
+        let (drain, _drained) = mpsc::channel(1);
+        let shutdown = Shutdown { signal: signal.clone(), _drain: drain };
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            serve_connection(server, Serv(app, signal, None), shutdown).await;
+            drop(stop);
+        });
+
+        let url = format!("ws://localhost{}", path);
+        let (stream, _) = tokio_tungstenite::client_async(url, client).await.unwrap();
+        stream
+    }
+
+    async fn receive(stream: &mut tokio_tungstenite::WebSocketStream<tokio::io::DuplexStream>) -> String {
+        use futures_util::StreamExt;
+        let next = tokio::time::timeout(Duration::from_secs(5), stream.next()).await.unwrap();
+        next.unwrap().unwrap().into_text().unwrap()
+    }
+
+    const SOCKETS: &str = "
+ws /echo => {you said $msg}
+ws /room/{name} => ws.broadcast {/room/$name} {$msg from $name}
+ws /lobby => ws.join {announcements} {joined}
+";
+
+    #[tokio::test]
+    async fn websocket_echo() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut socket = connect(app(SOCKETS), "/echo").await;
+        socket.send(Message::Text("hi".into())).await.unwrap();
+        assert_eq!(receive(&mut socket).await, "you said hi");
+    }
+
+    #[tokio::test]
+    async fn websocket_broadcast_to_route() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let app = app(SOCKETS);
+        let mut alice = connect(app.clone(), "/room/test").await;
+        let mut bob = connect(app.clone(), "/room/test").await;
+
+        alice.send(Message::Text("hello".into())).await.unwrap();
+        assert_eq!(receive(&mut bob).await, "hello from test");
+    }
+
+    #[tokio::test]
+    async fn websocket_join_topic() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut socket = connect(app(SOCKETS), "/lobby").await;
+        socket.send(Message::Text("hi".into())).await.unwrap();
+        assert_eq!(receive(&mut socket).await, "joined");
+
+        crate::functions::send("announcements", "welcome".to_owned());
+        assert_eq!(receive(&mut socket).await, "welcome");
+    }
+
+    #[tokio::test]
+    async fn websocket_runs_before_middleware() {
+        let source = "@before /private => {go away}\nws /private => {secret}";
+        let request = b"GET /private HTTP/1.1\r\nHost: x\r\nConnection: close\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let response = send(source, request, false).await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("go away"));
+    }
+
+    #[tokio::test]
+    async fn websocket_route_without_upgrade() {
+        let response = send(SOCKETS, b"GET /echo HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", false).await;
+        assert!(response.starts_with("HTTP/1.1 426"));
+    }
+
+    const SOURCE: &str = "
+/ => {hello}
+/panic => ? ({a}, {b}) {not a number}
+/bad_header => using (res.mime = {text/html
+oops}) {hello}
+";
+
+    #[tokio::test]
+    async fn valid_request() {
+        let response = send(SOURCE, b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", false).await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("hello"));
+    }
+
+    #[tokio::test]
+    async fn unknown_route() {
+        let response = send(SOURCE, b"GET /nothing HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", false).await;
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+
+    #[tokio::test]
+    async fn malformed_request_line() {
+        let response = send(SOURCE, b"GARBAGE\r\n\r\n", false).await;
+        assert!(response.starts_with("HTTP/1.1 400"));
+    }
+
+    #[tokio::test]
+    async fn malformed_header() {
+        let response = send(SOURCE, b"GET / HTTP/1.1\r\nHost: x\r\nnot a header\r\n\r\n", false).await;
+        assert!(response.starts_with("HTTP/1.1 400"));
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_in_path() {
+        let response = send(SOURCE, b"GET /\xff\xfe HTTP/1.1\r\nHost: x\r\n\r\n", false).await;
+        assert!(response.starts_with("HTTP/1.1 400"));
+    }
+
+    #[tokio::test]
+    async fn truncated_body() {
+        let request = b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 100\r\n\r\nshort";
+        let response = send(SOURCE, request, true).await;
+        assert!(!response.starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn invalid_response_header() {
+        let response = send(SOURCE, b"GET /bad_header HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", false).await;
+        assert!(response.starts_with("HTTP/1.1 500"));
+    }
+
+    #[tokio::test]
+    async fn panic_while_evaluating() {
+        let response = send(SOURCE, b"GET /panic HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", false).await;
+        assert!(response.starts_with("HTTP/1.1 500"));
+    }
+
+    const TEMPLATES: &str = "
+/escaped => {<p>$req.body</p>}
+/raw => {<p>$(raw req.body)</p>}
+/text => {$req.body}
+/plain => using (res.mime = {text/plain}) {$req.body}
+/inline => {hello <b>$req.body</b>}
+/html => using (res.mime = {text/html}) {hello $req.body}
+";
+
+    fn post(path: &str, body: &str) -> Vec<u8> {
+        format!("POST {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", path, body.len(), body).into_bytes()
+    }
+
+    #[tokio::test]
+    async fn html_templates_escape_values() {
+        let response = send(TEMPLATES, &post("/escaped", "<b>\"hi\"</b>"), false).await;
+        assert!(response.contains("<p>&lt;b&gt;&quot;hi&quot;&lt;/b&gt;</p>"));
+    }
+
+    #[tokio::test]
+    async fn html_output_escapes_values() {
+        let response = send(TEMPLATES, &post("/inline", "<i>hi</i>"), false).await;
+        assert!(response.contains("hello <b>&lt;i&gt;hi&lt;/i&gt;</b>"));
+
+        let response = send(TEMPLATES, &post("/html", "<i>hi</i>"), false).await;
+        assert!(response.contains("hello &lt;i&gt;hi&lt;/i&gt;"));
+    }
+
+    #[tokio::test]
+    async fn raw_values_are_not_escaped() {
+        let response = send(TEMPLATES, &post("/raw", "<b>hi</b>"), false).await;
+        assert!(response.contains("<p><b>hi</b></p>"));
+    }
+
+    #[tokio::test]
+    async fn untyped_responses_escape_values() {
+        // a browser would guess that a response without a content type is html
+        let response = send(TEMPLATES, &post("/text", "<b>hi</b>"), false).await;
+        assert!(response.contains("&lt;b&gt;hi&lt;/b&gt;"));
+
+        let response = send(TEMPLATES, &post("/plain", "<b>hi</b>"), false).await;
+        assert!(response.contains("<b>hi</b>") && !response.contains("&lt;"));
+    }
+
+    const MIDDLEWARE: &str = "
+@before /admin/{*rest} => {<h1>forbidden</h1>}
+@after => with.headers (\"X-Served-By\" = {serv})
+/ => {hello}
+/admin/panel => {secret}
+";
+
+    #[tokio::test]
+    async fn before_middleware_answers_request() {
+        let response = send(MIDDLEWARE, b"GET /admin/panel HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", false).await;
+        assert!(response.contains("forbidden"));
+        assert!(!response.contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn after_middleware_adds_headers() {
+        let response = send(MIDDLEWARE, b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", false).await;
+        assert!(response.to_lowercase().contains("x-served-by: serv"));
+        assert!(response.contains("hello"));
+    }
+
+    const CORS: &str = "
+server.cors.origins = list ({https://app.example.com})
+server.cors.max_age = 600
+/ => {hello}
+";
+
+    #[tokio::test]
+    async fn cors_preflight() {
+        let request = b"OPTIONS /anything HTTP/1.1\r\nHost: x\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: POST\r\nConnection: close\r\n\r\n";
+        let response = send(CORS, request, false).await.to_lowercase();
+        assert!(response.starts_with("http/1.1 204"));
+        assert!(response.contains("access-control-allow-origin: https://app.example.com"));
+        assert!(response.contains("access-control-max-age: 600"));
+    }
+
+    #[tokio::test]
+    async fn cors_headers_only_for_allowed_origins() {
+        let response = send(CORS, b"GET / HTTP/1.1\r\nHost: x\r\nOrigin: https://app.example.com\r\nConnection: close\r\n\r\n", false).await;
+        assert!(response.to_lowercase().contains("access-control-allow-origin: https://app.example.com"));
+
+        let response = send(CORS, b"GET / HTTP/1.1\r\nHost: x\r\nOrigin: https://evil.example.com\r\nConnection: close\r\n\r\n", false).await;
+        assert!(!response.to_lowercase().contains("access-control-allow-origin"));
+        assert!(response.contains("hello"));
+    }
+
+    #[test]
+    fn cors_credentials_need_origins() {
+        let mut scope = Stack::empty();
+        scope.insert_module(crate::functions::standard_library().values);
+        let module = crate::parser::parse_root_from_text("server.cors.credentials = true\n/ => {hello}\n", &mut scope).unwrap();
+        scope.insert_module(module.values.clone());
+
+        assert!(App::new(scope, &module).is_err());
+    }
+
+    #[tokio::test]
+    async fn query_strings_bind_named_params() {
+        let source = "
+sqlite.connect {:memory:}
+sqlite.run {create table t (x); insert into t values (1), (2), (3), (4), (5);}
+/search => sqlite.query {select x from t where x > cast(:min as integer);} req.query
+";
+        let request = b"GET /search?min=3 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n";
+        let response = send(source, request, false).await;
+        assert!(response.contains(r#""x": 4"#));
+        assert!(!response.contains(r#""x": 3"#));
+    }
+
+    #[tokio::test]
+    async fn constraint_violations_respond_with_409() {
+        let source = "
+sqlite.connect {:memory:}
+sqlite.run {create table users (name unique);}
+/alice => sqlite.run {insert into users values ('alice');}
+/twice => sqlite.run {insert into users values ('bob'); insert into users values ('bob');}
+";
+        assert!(send(source, &post("/alice", ""), false).await.starts_with("HTTP/1.1 200"));
+        assert!(send(source, &post("/twice", ""), false).await.starts_with("HTTP/1.1 409"));
+    }
+
+    const PAGES: &str = "
+sqlite.connect {:memory:}
+sqlite.run {create table t (x); insert into t values (1), (2), (3), (4), (5);}
+/pages/{n} => sqlite.page n 2 {select x from t order by x;}
+/pages => sqlite.page 2 2 {select x from t order by x;}
+/export => sqlite.stream {select x from t order by x;}
+/empty => sqlite.stream {select x from t where x > 5;}
+";
+
+    fn get(path: &str) -> Vec<u8> {
+        format!("GET {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", path).into_bytes()
+    }
+
+    /// The body of a chunked response, parsed as json
+    fn json_body(response: &str) -> json::JsonValue {
+        let mut rest = response.split_once("\r\n\r\n").unwrap().1;
+        let mut body = String::new();
+
+        loop {
+            let (size, after) = rest.split_once("\r\n").unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            if size == 0 { break };
+
+            body.push_str(&after[..size]);
+            rest = &after[size + 2..];
+        }
+
+        json::parse(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn page_links() {
+        let page = json_body(&send(PAGES, &get("/pages/2"), false).await);
+        assert_eq!(page["total"], 5);
+        assert_eq!(page["rows"].len(), 2);
+        assert_eq!(page["next"], "/pages/3");
+        assert_eq!(page["prev"], "/pages/1");
+
+        let last = json_body(&send(PAGES, &get("/pages/3"), false).await);
+        assert_eq!(last["rows"].len(), 1);
+        assert!(!last["next"].is_string());
+
+        // the page number in the query string is replaced, and the other parameters kept
+        let page = json_body(&send(PAGES, &get("/pages?sort=x&page=2"), false).await);
+        assert_eq!(page["next"], "/pages?sort=x&page=3");
+        assert_eq!(page["prev"], "/pages?sort=x&page=1");
+
+        let first = json_body(&send(PAGES, &get("/pages/1"), false).await);
+        assert_eq!(first["page"], 1);
+        assert!(!first["prev"].is_string());
+
+        assert!(send(PAGES, &get("/pages/9223372036854775807"), false).await.starts_with("HTTP/1.1 400"));
+    }
+
+    #[tokio::test]
+    async fn streamed_queries() {
+        let response = send(PAGES, &get("/export"), false).await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        let rows = json_body(&response);
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[4]["x"], 5);
+
+        assert_eq!(json_body(&send(PAGES, &get("/empty"), false).await).len(), 0);
+
+        // a database file is streamed from its own connection instead of one from the pool
+        let file = std::env::temp_dir().join(format!("serv-stream-{}.db", std::process::id()));
+        let source = PAGES.replace(":memory:", &file.to_string_lossy());
+        let rows = json_body(&send(&source, &get("/export"), false).await);
+        for suffix in ["", "-wal", "-shm"] {
+            _ = std::fs::remove_file(format!("{}{}", file.display(), suffix));
+        }
+        assert_eq!(rows.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_route() {
+        let source = "/limited => ratelimit 2/min {ok}";
+        let request = b"GET /limited HTTP/1.1\r\nHost: x\r\nX-Forwarded-For: 10.0.0.1\r\nConnection: close\r\n\r\n";
+
+        assert!(send(source, request, false).await.starts_with("HTTP/1.1 200"));
+        assert!(send(source, request, false).await.starts_with("HTTP/1.1 200"));
+
+        let response = send(source, request, false).await;
+        assert!(response.starts_with("HTTP/1.1 429"));
+        assert!(response.to_lowercase().contains("retry-after: 30"));
+
+        // other clients have their own limit
+        let other = b"GET /limited HTTP/1.1\r\nHost: x\r\nX-Forwarded-For: 10.0.0.2\r\nConnection: close\r\n\r\n";
+        assert!(send(source, other, false).await.starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn rate_limits_are_per_route() {
+        let source = "/items/{id} => ratelimit 2/min {ok}";
+        let request = |id: i64| format!("GET /items/{} HTTP/1.1\r\nHost: x\r\nX-Forwarded-For: 10.0.0.3\r\nConnection: close\r\n\r\n", id).into_bytes();
+
+        // asking for a different item each time doesn't get around the limit
+        assert!(send(source, &request(1), false).await.starts_with("HTTP/1.1 200"));
+        assert!(send(source, &request(2), false).await.starts_with("HTTP/1.1 200"));
+        assert!(send(source, &request(3), false).await.starts_with("HTTP/1.1 429"));
+    }
+
+    fn get_with_auth(path: &str, authorization: &str) -> Vec<u8> {
+        format!("GET {} HTTP/1.1\r\nHost: x\r\nAuthorization: {}\r\nConnection: close\r\n\r\n", path, authorization).into_bytes()
+    }
+
+    #[tokio::test]
+    async fn basic_auth() {
+        use base64::Engine;
+
+        // `$` starts an expression in a template, so it is escaped as `$$`
+        let hash = bcrypt::hash("hunter2", 4).unwrap().replace('$', "$$");
+        let source = format!("/admin => auth.basic (alice = {{{}}}) {{hello $auth.user}}", hash);
+
+        let response = send(&source, b"GET /admin HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", false).await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+        assert!(response.to_lowercase().contains("www-authenticate: basic"));
+
+        let wrong = base64::engine::general_purpose::STANDARD.encode("alice:hunter3");
+        let response = send(&source, &get_with_auth("/admin", &format!("Basic {}", wrong)), false).await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+
+        // unknown users are checked against alice's hash, but never let in with her password
+        let unknown = base64::engine::general_purpose::STANDARD.encode("mallory:hunter2");
+        let response = send(&source, &get_with_auth("/admin", &format!("Basic {}", unknown)), false).await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+
+        let right = base64::engine::general_purpose::STANDARD.encode("alice:hunter2");
+        let response = send(&source, &get_with_auth("/admin", &format!("Basic {}", right)), false).await;
+        assert!(response.contains("hello alice"));
+    }
+
+    #[tokio::test]
+    async fn bearer_auth() {
+        let source = "/api => auth.bearer (abc123 = {alice}) {hello $auth.user}";
+
+        let response = send(source, &get_with_auth("/api", "Bearer nope"), false).await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+        assert!(response.to_lowercase().contains("www-authenticate: bearer"));
+
+        let response = send(source, &get_with_auth("/api", "Bearer abc123"), false).await;
+        assert!(response.contains("hello alice"));
+    }
+
+    #[tokio::test]
+    async fn bearer_auth_fails_closed() {
+        let response = send("/api => auth.bearer {abc123} {ok}", &get_with_auth("/api", "Bearer abc12"), false).await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+
+        let response = send("/api => auth.bearer {abc123} {ok}", &get_with_auth("/api", "Bearer abc123"), false).await;
+        assert!(response.contains("ok"));
+
+        // a lookup that doesn't say which user the token belongs to doesn't let anyone in
+        let response = send("/api => auth.bearer 1 {ok}", &get_with_auth("/api", "Bearer abc123"), false).await;
+        assert!(response.starts_with("HTTP/1.1 500"));
+    }
+
+    const API_KEYS: &str = "
+sqlite.connect {:memory:}
+sqlite.run {create table api_keys (name, token); insert into api_keys values ('alice', 'abc123');}
+api_user = sqlite.query {select name, token from api_keys where token = $auth.token;}
+every_user = sqlite.query {select name, token from api_keys;}
+tokenless = sqlite.query {select name from api_keys;}
+/api => auth.bearer api_user {hello $auth.user}
+/unfiltered => auth.bearer every_user {hello $auth.user}
+/tokenless => auth.bearer tokenless {hello $auth.user}
+";
+
+    #[tokio::test]
+    async fn bearer_auth_with_a_query() {
+        let response = send(API_KEYS, &get_with_auth("/api", "Bearer abc123"), false).await;
+        assert!(response.contains("hello alice"));
+
+        let response = send(API_KEYS, &get_with_auth("/api", "Bearer nope"), false).await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+
+        // rows are checked against the token even when the query doesn't filter on it
+        let response = send(API_KEYS, &get_with_auth("/unfiltered", "Bearer nope"), false).await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+
+        let response = send(API_KEYS, &get_with_auth("/unfiltered", "Bearer abc123"), false).await;
+        assert!(response.contains("hello alice"));
+
+        let response = send(API_KEYS, &get_with_auth("/tokenless", "Bearer nope"), false).await;
+        assert!(response.starts_with("HTTP/1.1 500"));
+    }
+}
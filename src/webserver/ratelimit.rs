@@ -0,0 +1,41 @@
+use crate::{ServValue, ServError, Stack};
+use crate::engine;
+use crate::functions::{Rate, take, client_key};
+
+use hyper::http::request::Parts;
+
+/// A limit on how many requests each client can make to the whole server, from `server.rate_limit`
+pub struct RateLimit {
+    rate: Rate,
+    key: String,
+}
+
+impl RateLimit {
+    pub fn from_scope(scope: &Stack) -> Result<Option<Self>, ServError> {
+        // `server.rate_limit = {100/min}` is short for setting only the rate
+        if let Ok(rate @ ServValue::Text(_)) = engine::resolve_key("server.rate_limit", scope) {
+            return Ok(Some(Self { rate: Rate::parse(&rate.to_string())?, key: "ip".to_owned() }))
+        }
+
+        let Some(config) = super::get_config_table("server.rate_limit", scope)? else { return Ok(None) };
+
+        let rate = match config.get("rate") {
+            Some(rate) => Rate::parse(&rate.to_string())?,
+            None => return Err(ServError::new(500, "server.rate_limit needs a rate, ie. server.rate_limit.rate = {100/min}")),
+        };
+
+        let key = config.get("key").map(ServValue::to_string).unwrap_or("ip".to_owned());
+
+        // check the key now, rather than failing on every request
+        if !(key == "ip" || key.starts_with("header:") || key.starts_with("cookie:")) {
+            return Err(ServError::new(500, &format!("invalid server.rate_limit.key {}, expected ip, header:Name or cookie:Name", key)))
+        }
+
+        Ok(Some(Self { rate, key }))
+    }
+
+    /// Fail with a 429 if the client a request came from has used up its limit
+    pub fn check(&self, parts: &Parts) -> Result<(), ServError> {
+        take(&format!("*|{}", client_key(&self.key, parts)?), self.rate)
+    }
+}
@@ -0,0 +1,206 @@
+use crate::{ServValue, ServError};
+
+use matchit::Router;
+use regex::Regex;
+
+/// What a typed parameter like `{id:int}` has to look like in order to match
+#[derive(Clone)]
+enum Constraint {
+    Any,
+    Int,
+    Float,
+    Uuid,
+    Pattern(Regex),
+}
+
+impl Constraint {
+    fn parse(text: Option<&str>) -> Result<Self, ServError> {
+        Ok(match text {
+            None          => Constraint::Any,
+            Some("int")   => Constraint::Int,
+            Some("float") => Constraint::Float,
+            Some("uuid")  => Constraint::Uuid,
+            Some(pattern) => {
+                let regex = Regex::new(&format!("^(?:{})$", pattern))
+                    .map_err(|e| ServError::new(500, &format!("invalid route pattern {}: {}", pattern, e)))?;
+                Constraint::Pattern(regex)
+            },
+        })
+    }
+
+    /// Convert a matched parameter into a value, or None if it doesn't fit the constraint
+    fn convert(&self, text: &str) -> Option<ServValue> {
+        match self {
+            Constraint::Any   => Some(ServValue::Text(text.into())),
+            Constraint::Int   => text.parse().ok().map(ServValue::Int),
+            Constraint::Float => text.parse().ok().filter(|f: &f64| f.is_finite()).map(ServValue::Float),
+            Constraint::Uuid  => is_uuid(text).then(|| ServValue::Text(text.to_lowercase().as_str().into())),
+            Constraint::Pattern(r) => r.is_match(text).then(|| ServValue::Text(text.into())),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Constraint::Any   => String::new(),
+            Constraint::Int   => ":int".to_owned(),
+            Constraint::Float => ":float".to_owned(),
+            Constraint::Uuid  => ":uuid".to_owned(),
+            Constraint::Pattern(r) => format!(":{}", r.as_str()),
+        }
+    }
+}
+
+fn is_uuid(text: &str) -> bool {
+    text.len() == 36 && text.char_indices().all(|(i, c)| match i {
+        8 | 13 | 18 | 23 => c == '-',
+        _ => c.is_ascii_hexdigit(),
+    })
+}
+
+/// A route along with the names and constraints of its parameters, in the order they appear
+struct Route {
+    pattern: String,
+    params: Vec<(String, Constraint)>,
+    handler: ServValue,
+}
+
+/// Routes with typed parameters. Parameters are given to matchit by position, so that routes
+/// which only differ in the types of their parameters, like `/users/{id:int}` and `/users/{name}`,
+/// can share a path and are tried in the order they were declared.
+pub struct Routes(Router<Vec<Route>>);
+
+/// Split a route pattern into the path given to matchit, and the names and constraints of
+/// its parameters. `/users/{id:int}` becomes `/users/{0}` with a parameter `id` that must be an int.
+fn parse_pattern(pattern: &str) -> Result<(String, Vec<(String, Constraint)>), ServError> {
+    let mut path = String::new();
+    let mut params = Vec::new();
+    let mut chars = pattern.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if c != '{' {
+            path.push(c);
+            continue
+        }
+
+        // an escaped bracket, which matches a literal `{`
+        if chars.next_if(|(_, c)| *c == '{').is_some() {
+            path.push_str("{{");
+            continue
+        }
+
+        // brackets can be nested inside of a regex, ie. `{year:[0-9]{4}}`
+        let mut depth = 1;
+        let end = chars.by_ref().find(|(_, c)| {
+            match c { '{' => depth += 1, '}' => depth -= 1, _ => {} };
+            depth == 0
+        });
+
+        let Some((end, _)) = end else {
+            return Err(ServError::new(500, &format!("unclosed parameter in route {}", pattern)))
+        };
+
+        let inner = &pattern[i + 1..end];
+        let (name, constraint) = match inner.split_once(':') {
+            Some((name, constraint)) => (name, Some(constraint)),
+            None => (inner, None),
+        };
+
+        let catch_all = name.starts_with('*');
+        if catch_all { path.push_str(&format!("{{*{}}}", params.len())) }
+        else { path.push_str(&format!("{{{}}}", params.len())) };
+
+        params.push((name.trim_start_matches('*').to_owned(), Constraint::parse(constraint)?));
+    }
+
+    Ok((path, params))
+}
+
+impl Routes {
+    pub fn new() -> Self {
+        Self(Router::new())
+    }
+
+    /// Add a route, failing if it conflicts with one that was already added
+    pub fn insert(&mut self, pattern: &str, handler: ServValue) -> Result<(), ServError> {
+        let (path, params) = parse_pattern(pattern)?;
+        let route = Route { pattern: pattern.to_owned(), params, handler };
+
+        if let Ok(existing) = self.0.at_mut(&path) {
+            // matchit matched the pattern itself, so check that it is the same route and not
+            // a different route whose parameters happen to match the text of this one
+            if existing.value.first().is_some_and(|r| parse_pattern(&r.pattern).is_ok_and(|(p, _)| p == path)) {
+                let same = existing.value.iter().find(|r| same_constraints(r, &route));
+                if let Some(other) = same {
+                    return Err(ServError::new(500, &format!("route {} conflicts with {}", pattern, other.pattern)))
+                }
+
+                existing.value.push(route);
+                return Ok(())
+            }
+        }
+
+        self.0.insert(path, vec![route]).map_err(|e| match e {
+            matchit::InsertError::Conflict { with } => ServError::new(500, &format!("route {} conflicts with {}", pattern, with)),
+            e => ServError::new(500, &format!("invalid route {}: {}", pattern, e)),
+        })
+    }
+
+    /// Find the first route that matches a path, along with its converted parameters and the
+    /// pattern it was declared with
+    pub fn at(&self, path: &str) -> Option<(ServValue, Vec<(String, ServValue)>, &str)> {
+        let matched = self.0.at(path).ok()?;
+
+        'routes: for route in matched.value.iter() {
+            let mut params = Vec::new();
+            for (index, (name, constraint)) in route.params.iter().enumerate() {
+                let text = matched.params.get(index.to_string()).unwrap_or_default();
+                let Some(value) = constraint.convert(text) else { continue 'routes };
+                params.push((name.clone(), value));
+            }
+
+            return Some((route.handler.clone(), params, &route.pattern))
+        }
+
+        None
+    }
+}
+
+fn same_constraints(a: &Route, b: &Route) -> bool {
+    a.params.len() == b.params.len() && a.params.iter().zip(b.params.iter()).all(|((_, x), (_, y))| x.describe() == y.describe())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(patterns: &[&str]) -> Result<Routes, ServError> {
+        let mut output = Routes::new();
+        for pattern in patterns {
+            output.insert(pattern, ServValue::Text((*pattern).into()))?;
+        }
+
+        Ok(output)
+    }
+
+    #[test]
+    fn typed_parameters() {
+        let routes = routes(&["/users/{id:int}", "/users/{name}", "/posts/{slug:[a-z-]+}"]).unwrap();
+
+        let (handler, params, pattern) = routes.at("/users/12").unwrap();
+        assert_eq!(handler.to_string(), "/users/{id:int}");
+        assert!(matches!(params[0].1, ServValue::Int(12)));
+
+        let (handler, _, _) = routes.at("/users/abc").unwrap();
+        assert_eq!(handler.to_string(), "/users/{name}");
+
+        assert!(routes.at("/posts/hello-world").is_some());
+        assert!(routes.at("/posts/Hello").is_none());
+    }
+
+    #[test]
+    fn conflicting_routes() {
+        assert!(routes(&["/users/{id:int}", "/users/{other:int}"]).is_err());
+        assert!(routes(&["/users/{id}", "/users/{name}"]).is_err());
+        assert!(routes(&["/a/{x:[0-9]{4}}", "/a/{y:uuid}"]).is_ok());
+    }
+}
@@ -0,0 +1,33 @@
+use super::{ServBody, App};
+
+use crate::{ServValue, ServError, Stack};
+
+use hyper::Response;
+use tokio::sync::mpsc;
+use std::sync::Arc;
+
+/// Build a streaming json response from the `res.stream` table that `sqlite.stream` leaves in
+/// the scope of a request. The query runs on its own blocking thread, sending rows as they are
+/// read, so the response starts before the query has finished.
+pub fn from_config(config: ServValue, app: &Arc<App>, scope: &Stack) -> Result<Response<ServBody>, ServError> {
+    let config = config.expect_module()?;
+    let (app, words, request) = (app.clone(), scope.words.clone(), scope.request.clone());
+    let (sender, receiver) = mpsc::channel(16);
+
+    tokio::task::spawn_blocking(move || {
+        let mut scope = app.scope.make_child();
+        scope.words = words;
+        scope.request = request;
+
+        // fails once the client has disconnected and the body has been dropped
+        let send = |chunk: String| sender.blocking_send(chunk.into_bytes().into()).is_ok();
+        if let Err(e) = crate::functions::stream_query(config, &scope, send) {
+            // the status has already been sent, so all that can be done is to cut the response short
+            eprintln!("error in query stream: {}", e);
+        }
+    });
+
+    let mut response = Response::new(ServBody::Stream(receiver));
+    response.headers_mut().insert("Content-Type", "application/json".parse().unwrap());
+    Ok(response)
+}
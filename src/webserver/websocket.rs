@@ -0,0 +1,181 @@
+use super::{ServBody, App, eval_route, response_from_error};
+
+use crate::{ServValue, ServError, Label};
+use crate::engine;
+use crate::value::{Serializer, DefaultSerializer};
+
+use hyper::{Request, Response, StatusCode};
+use hyper::body::Incoming as IncomingBody;
+use hyper::http::request::Parts;
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use futures_util::{SinkExt, StreamExt};
+
+use tokio::sync::{broadcast, mpsc, watch};
+use crate::functions::Subscription;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Whether a request is asking to be upgraded to a websocket
+pub fn is_upgrade(parts: &Parts) -> bool {
+    parts.headers.get("Upgrade")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// A route that only accepts websocket connections was requested without an upgrade
+pub fn upgrade_required() -> Response<ServBody> {
+    let mut response = Response::new(ServBody::from_text("this route only accepts websocket connections"));
+    *response.status_mut() = StatusCode::UPGRADE_REQUIRED;
+    response.headers_mut().insert("Upgrade", "websocket".parse().unwrap());
+    response
+}
+
+/// Everything a websocket needs to evaluate its handler for each message
+struct Socket {
+    app: Arc<App>,
+    handler: ServValue,
+    words: HashMap<Label, ServValue>,
+    request: Parts,
+}
+
+/// The result of evaluating the handler for one message
+struct Reply {
+    data: Option<String>,
+    join: Option<String>,
+}
+
+impl Socket {
+    /// Evaluate the handler with `msg` bound to the incoming message
+    fn evaluate(&self, msg: ServValue) -> Result<Reply, ServError> {
+        let mut scope = self.app.scope.make_child();
+        scope.words = self.words.clone();
+        scope.request = Some(self.request.clone());
+        scope.insert("msg", msg)?;
+
+        let value = eval_route(self.handler.clone(), &mut scope)?;
+        let join = engine::deref(&"res.ws.join".into(), &scope).ok().map(|v| v.to_string());
+
+        let data = match value {
+            ServValue::None => None,
+            value => {
+                let mut output = String::new();
+                DefaultSerializer(&scope).write(value, &mut output)?;
+                Some(output)
+            },
+        };
+
+        Ok(Reply { data, join })
+    }
+
+    /// Evaluate the handler on a blocking thread, logging any errors
+    async fn respond(self: &Arc<Self>, msg: ServValue, path: &str) -> Reply {
+        let socket = self.clone();
+        match tokio::task::spawn_blocking(move || socket.evaluate(msg)).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(e)) => { eprintln!("ws {}: {}", path, e); Reply { data: None, join: None } },
+            Err(_) => { eprintln!("ws {}: panicked while evaluating handler", path); Reply { data: None, join: None } },
+        }
+    }
+
+    /// Read messages until the client disconnects or the server shuts down, sending back handler
+    /// results along with everything broadcast to the channels this socket is subscribed to
+    async fn run(self, stream: WebSocketStream<TokioIo<Upgraded>>, path: String, subscription: Subscription, mut stop: watch::Receiver<bool>) {
+        let socket = Arc::new(self);
+        let (mut sink, mut stream) = stream.split();
+        let (outbox, mut inbox) = mpsc::channel(16);
+        subscribe(subscription, outbox.clone());
+
+        loop {
+            tokio::select! {
+                message = stream.next() => {
+                    let msg = match message {
+                        Some(Ok(Message::Text(text))) => ServValue::Text(text.as_str().into()),
+                        Some(Ok(Message::Binary(bytes))) => ServValue::Text(bytes::Bytes::from(bytes).into()),
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+
+                        // pings are answered by tungstenite itself
+                        Some(Ok(_)) => continue,
+                    };
+
+                    let reply = socket.respond(msg, &path).await;
+                    if let Some(topic) = reply.join {
+                        subscribe(crate::functions::subscribe(&topic), outbox.clone());
+                    }
+
+                    if let Some(data) = reply.data {
+                        if sink.send(Message::Text(data)).await.is_err() { break };
+                    }
+                },
+
+                Some(data) = inbox.recv() => {
+                    if sink.send(Message::Text(data)).await.is_err() { break };
+                },
+
+                _ = super::events::stopped(&mut stop) => {
+                    _ = sink.send(Message::Close(None)).await;
+                    break
+                },
+            }
+        }
+    }
+}
+
+/// Forward a channel into the outbox of a socket, until either of them closes
+fn subscribe(mut source: Subscription, outbox: mpsc::Sender<String>) {
+    tokio::task::spawn(async move {
+        loop {
+            let data = tokio::select! {
+                message = source.recv() => match message {
+                    Ok(data) => data,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = outbox.closed() => break,
+            };
+
+            if outbox.send(data).await.is_err() { break };
+        }
+    });
+}
+
+/// Accept the websocket handshake for a request, and spawn a task that runs the socket once
+/// hyper hands over the connection. The handler is evaluated with `words` in scope, which are
+/// the parameters of the route and anything `@before` middleware added.
+pub fn upgrade(mut req: Request<IncomingBody>, handler: ServValue, words: HashMap<Label, ServValue>, app: Arc<App>, stop: watch::Receiver<bool>) -> Response<ServBody> {
+    let Some(key) = req.headers().get("Sec-WebSocket-Key") else {
+        return response_from_error(ServError::new(400, "missing Sec-WebSocket-Key header"))
+    };
+
+    let accept = derive_accept_key(key.as_bytes());
+    let path = req.uri().path().to_owned();
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    let (request, _) = req.into_parts();
+
+    // subscribe before answering, so that nothing broadcast after the handshake is missed
+    let subscription = crate::functions::subscribe(&path);
+    let socket = Socket { app, handler, words, request };
+
+    tokio::task::spawn(async move {
+        let upgraded = match on_upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(e) => return eprintln!("ws {}: upgrade failed: {}", path, e),
+        };
+
+        let stream = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+        socket.run(stream, path, subscription, stop).await;
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", accept)
+        .body(ServBody::empty())
+        .unwrap_or_else(|e| response_from_error(e.into()))
+}